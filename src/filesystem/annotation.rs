@@ -0,0 +1,204 @@
+//! User metadata of catalog entries. System metadata (hash, size, storage path and
+//! digests) is written once by the library and never replaced; annotations such as
+//! notes, tags or ratings live in their own table and stay editable for the whole life
+//! of an entry. On immutable entries, annotations can only be added: an existing one can
+//! be neither changed nor removed until the flag is cleared.
+//!
+//! The `rating` and `review_state` annotations are reserved for the triage workflow
+//! which usually follows a large import, and can only be written through their
+//! dedicated setters, which validate them.
+use rusqlite::{params, Connection, OptionalExtension};
+use crate::filesystem::changes::{self, ChangeKind};
+use crate::filesystem::error::{AppCustomErrorKind, AppError, AppResult};
+use crate::filesystem::freeze;
+use crate::filesystem::immutability;
+use crate::filesystem::repository::Repository;
+
+const RATING_KEY: &str = "rating";
+const REVIEW_STATE_KEY: &str = "review_state";
+const MAX_RATING: u8 = 5;
+
+/// Triage state of an entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ReviewState {
+    Unreviewed,
+    Kept,
+    FlaggedForDeletion,
+}
+
+impl ReviewState {
+    /// Stored value, none for unreviewed entries
+    fn as_value(&self) -> Option<&'static str> {
+        match self {
+            ReviewState::Unreviewed => None,
+            ReviewState::Kept => Some("kept"),
+            ReviewState::FlaggedForDeletion => Some("flagged_for_deletion"),
+        }
+    }
+
+    fn parse(value: Option<&str>) -> Option<ReviewState> {
+        match value {
+            None => Some(ReviewState::Unreviewed),
+            Some("kept") => Some(ReviewState::Kept),
+            Some("flagged_for_deletion") => Some(ReviewState::FlaggedForDeletion),
+            _ => None,
+        }
+    }
+}
+
+fn metadata_error(msg: &str) -> AppError {
+    AppError::new_custom(AppCustomErrorKind::RepositoryMetadata, msg)
+}
+
+fn check_rating(rating: u8) -> AppResult<()> {
+    if rating > MAX_RATING {
+        return Err(metadata_error(&format!("rating {} is not between 0 and {}", rating, MAX_RATING)));
+    }
+    Ok(())
+}
+
+fn is_reserved(key: &str) -> bool {
+    key == RATING_KEY || key == REVIEW_STATE_KEY
+}
+
+/// Check a value of a reserved annotation, as its setter would store it
+pub(crate) fn check_reserved(key: &str, value: &str) -> AppResult<()> {
+    match key {
+        RATING_KEY => value.parse()
+            .map_err(|_| metadata_error(&format!("rating {:?} is not a number", value)))
+            .and_then(check_rating),
+        REVIEW_STATE_KEY => match ReviewState::parse(Some(value)) {
+            Some(_) => Ok(()),
+            None => Err(metadata_error(&format!("unknown review state {:?}", value))),
+        },
+        _ => Ok(()),
+    }
+}
+
+/// Whether setting `key` to `value` changes the annotations of an entry. Fails if it
+/// would change or remove an existing annotation of an immutable entry.
+pub(crate) fn annotation_changes(conn: &Connection, id: &str, key: &str, value: Option<&str>) -> AppResult<bool> {
+    let existing: Option<String> = conn
+        .query_row("SELECT value FROM entry_annotation WHERE entry_id = ?1 AND key = ?2", [id, key], |row| row.get(0))
+        .optional()
+        .map_err(|e| AppError::from_error(e, &format!("looking up annotation {} of entry {}", key, id)))?;
+    if existing.as_deref() == value {
+        return Ok(false);
+    }
+    if existing.is_some() {
+        immutability::ensure_mutable(conn, id)?;
+    }
+    Ok(true)
+}
+
+/// Set or remove an annotation, recording a change only if it differs
+pub(crate) fn write_annotation(conn: &Connection, now: &str, id: &str, key: &str, value: Option<&str>) -> AppResult<()> {
+    if !annotation_changes(conn, id, key, value)? {
+        return Ok(());
+    }
+    match value {
+        Some(value) => conn.execute(
+            "INSERT INTO entry_annotation (entry_id, key, value, created, modified) VALUES (?1, ?2, ?3, ?4, ?4)
+             ON CONFLICT(entry_id, key) DO UPDATE SET value = excluded.value, modified = excluded.modified",
+            params![id, key, value, now]),
+        None => conn.execute(
+            "DELETE FROM entry_annotation WHERE entry_id = ?1 AND key = ?2",
+            params![id, key]),
+    }.map_err(|e| AppError::from_error(e, &format!("annotating {} of entry {}", key, id)))?;
+    changes::record(conn, now, ChangeKind::AnnotationChanged, id)
+}
+
+impl Repository {
+    /// Set the annotation `key` of an entry, or remove it when `value` is `None`.
+    /// Reserved keys are refused; use `set_rating` and `set_review_state` instead.
+    pub fn annotate(&self, id: &str, key: &str, value: Option<&str>) -> AppResult<()> {
+        if is_reserved(key) {
+            return Err(metadata_error(&format!("annotation {} is reserved", key)));
+        }
+        self.annotate_all(&[id], key, value)
+    }
+
+    /// Set or remove the same annotation on several entries at once. Nothing is changed
+    /// if any of the entries is unknown.
+    fn annotate_all(&self, ids: &[&str], key: &str, value: Option<&str>) -> AppResult<()> {
+        freeze::ensure_writable(self.database().connection())?;
+        for id in ids {
+            immutability::ensure_entry_exists(self.database().connection(), id)?;
+        }
+        let now = self.timestamp()?;
+        let tx = self.database().connection().unchecked_transaction()
+            .map_err(|e| AppError::from_error(e, "starting annotation"))?;
+        for id in ids {
+            write_annotation(&tx, &now, id, key, value)?;
+        }
+        tx.commit().map_err(|e| AppError::from_error(e, "committing annotation"))
+    }
+
+    /// Annotations of an entry as `(key, value)` pairs, sorted by key
+    pub fn annotations(&self, id: &str) -> AppResult<Vec<(String, String)>> {
+        let mut stmt = self.database().connection()
+            .prepare("SELECT key, value FROM entry_annotation WHERE entry_id = ?1 ORDER BY key")
+            .map_err(|e| AppError::from_error(e, "preparing annotation query"))?;
+        stmt.query_map([id], |row| Ok((row.get(0)?, row.get(1)?)))
+            .and_then(|rows| rows.collect())
+            .map_err(|e| AppError::from_error(e, &format!("reading annotations of {}", id)))
+    }
+
+    fn annotation(&self, id: &str, key: &str) -> AppResult<Option<String>> {
+        Ok(self.annotations(id)?.into_iter().find(|(k, _)| k == key).map(|(_, value)| value))
+    }
+
+    /// Rating of an entry, from 0 to 5, if it was rated
+    pub fn rating(&self, id: &str) -> AppResult<Option<u8>> {
+        self.annotation(id, RATING_KEY)?
+            .map(|value| value.parse().map_err(|e| AppError::from_error(e, &format!("reading rating of {}", id))))
+            .transpose()
+    }
+
+    /// Rate entries from 0 to 5, or clear their rating
+    pub fn set_rating(&self, ids: &[&str], rating: Option<u8>) -> AppResult<()> {
+        if let Some(rating) = rating {
+            check_rating(rating)?;
+        }
+        self.annotate_all(ids, RATING_KEY, rating.map(|r| r.to_string()).as_deref())
+    }
+
+    pub fn review_state(&self, id: &str) -> AppResult<ReviewState> {
+        let value = self.annotation(id, REVIEW_STATE_KEY)?;
+        ReviewState::parse(value.as_deref())
+            .ok_or_else(|| metadata_error(&format!("unknown review state of {}", id)))
+    }
+
+    /// Move entries to a review state
+    pub fn set_review_state(&self, ids: &[&str], state: ReviewState) -> AppResult<()> {
+        self.annotate_all(ids, REVIEW_STATE_KEY, state.as_value())
+    }
+
+    /// Ids of the entries in a review state
+    pub fn entries_in_review_state(&self, state: ReviewState) -> AppResult<Vec<String>> {
+        self.filter_entries(
+            "SELECT id FROM main_catalog
+             WHERE coalesce((SELECT value FROM entry_annotation WHERE entry_id = main_catalog.id AND key = ?1), '') = ?2
+             ORDER BY id",
+            params![REVIEW_STATE_KEY, state.as_value().unwrap_or("")])
+    }
+
+    /// Ids of the entries rated `min` or more
+    pub fn entries_rated_at_least(&self, min: u8) -> AppResult<Vec<String>> {
+        self.filter_entries(
+            "SELECT entry_id FROM entry_annotation
+             WHERE key = ?1 AND CAST(value AS INTEGER) >= ?2
+             ORDER BY entry_id",
+            params![RATING_KEY, min])
+    }
+
+    fn filter_entries<P: rusqlite::Params>(&self, sql: &str, params: P) -> AppResult<Vec<String>> {
+        let mut stmt = self.database().connection()
+            .prepare(sql)
+            .map_err(|e| AppError::from_error(e, "preparing entry filter"))?;
+        stmt.query_map(params, |row| row.get(0))
+            .and_then(|rows| rows.collect())
+            .map_err(|e| AppError::from_error(e, "filtering entries"))
+    }
+}
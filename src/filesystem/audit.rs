@@ -0,0 +1,60 @@
+//! Append-only record of sensitive operations performed on the repository.
+use rusqlite::{params, Connection};
+use crate::filesystem::error::{AppError, AppResult};
+use crate::filesystem::repository::Repository;
+
+/// A single audit log record
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditRecord {
+    action: String,
+    entry_id: Option<String>,
+    detail: Option<String>,
+    created: String,
+}
+
+impl AuditRecord {
+    /// Name of the operation, e.g. `shred`
+    pub fn action(&self) -> &str {
+        &self.action
+    }
+
+    /// Entry affected by the operation, if any
+    pub fn entry_id(&self) -> Option<&str> {
+        self.entry_id.as_deref()
+    }
+
+    pub fn detail(&self) -> Option<&str> {
+        self.detail.as_deref()
+    }
+
+    /// UTC timestamp of the record, as `YYYY-MM-DD HH:MM:SS`
+    pub fn created(&self) -> &str {
+        &self.created
+    }
+}
+
+/// Append a record, typically inside the transaction of the audited operation
+pub(crate) fn record(conn: &Connection, now: &str, action: &str, entry_id: Option<&str>, detail: Option<&str>) -> AppResult<()> {
+    conn.execute(
+        "INSERT INTO audit_log (action, entry_id, detail, created) VALUES (?1, ?2, ?3, ?4)",
+        params![action, entry_id, detail, now])
+        .map_err(|e| AppError::from_error(e, &format!("recording {} in audit log", action)))?;
+    Ok(())
+}
+
+impl Repository {
+    /// All audit records, oldest first
+    pub fn audit_log(&self) -> AppResult<Vec<AuditRecord>> {
+        let mut stmt = self.database().connection()
+            .prepare("SELECT action, entry_id, detail, created FROM audit_log ORDER BY id")
+            .map_err(|e| AppError::from_error(e, "preparing audit log query"))?;
+        let rows = stmt.query_map([], |row| Ok(AuditRecord {
+            action: row.get(0)?,
+            entry_id: row.get(1)?,
+            detail: row.get(2)?,
+            created: row.get(3)?,
+        }));
+        rows.and_then(|rows| rows.collect())
+            .map_err(|e| AppError::from_error(e, "reading audit log"))
+    }
+}
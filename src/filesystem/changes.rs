@@ -0,0 +1,228 @@
+//! Change feed of the catalog. Every mutation of an entry appends an operation to the
+//! `oplog` table, numbered by a monotonically increasing sequence, in the same
+//! transaction as the mutation itself. Indexers and sync clients tail the feed by
+//! asking for the changes after the last sequence number they processed, or subscribe
+//! to receive them as they happen. Operations unknown to this version, recorded by a
+//! newer one, are skipped.
+use std::fmt;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use rusqlite::{params, Connection};
+use crate::filesystem::error::{AppCustomErrorKind, AppError, AppResult};
+use crate::filesystem::repository::{Repository, RepositoryDB};
+
+/// Number of changes read by a subscription at each poll
+const SUBSCRIPTION_BATCH: usize = 1000;
+
+/// Position in the change feed. The default value is before the first change.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SequenceNumber(u64);
+
+impl SequenceNumber {
+    pub fn value(&self) -> u64 {
+        self.0
+    }
+}
+
+impl From<u64> for SequenceNumber {
+    fn from(value: u64) -> Self {
+        SequenceNumber(value)
+    }
+}
+
+impl fmt::Display for SequenceNumber {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Kind of catalog mutation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ChangeKind {
+    /// A provisional entry was imported from a checksum list
+    ProvisionalAdded,
+    /// An additional digest was recorded for an entry
+    DigestRecorded,
+    /// An entry was erased, leaving a tombstone
+    Shredded,
+    /// An entry was flagged or unflagged immutable
+    ImmutabilityChanged,
+    /// A user annotation of an entry was set or removed
+    AnnotationChanged,
+    /// A relation from an entry was added or removed
+    RelationChanged,
+}
+
+impl ChangeKind {
+    const ALL: [ChangeKind; 6] = [
+        ChangeKind::ProvisionalAdded,
+        ChangeKind::DigestRecorded,
+        ChangeKind::Shredded,
+        ChangeKind::ImmutabilityChanged,
+        ChangeKind::AnnotationChanged,
+        ChangeKind::RelationChanged,
+    ];
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            ChangeKind::ProvisionalAdded => "provisional_added",
+            ChangeKind::DigestRecorded => "digest_recorded",
+            ChangeKind::Shredded => "shredded",
+            ChangeKind::ImmutabilityChanged => "immutability_changed",
+            ChangeKind::AnnotationChanged => "annotation_changed",
+            ChangeKind::RelationChanged => "relation_changed",
+        }
+    }
+
+    fn parse(operation: &str) -> Option<ChangeKind> {
+        ChangeKind::ALL.into_iter().find(|kind| kind.as_str() == operation)
+    }
+}
+
+/// A catalog mutation from the change feed
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    seq: SequenceNumber,
+    kind: ChangeKind,
+    entry_id: String,
+    created: String,
+}
+
+impl Change {
+    pub fn seq(&self) -> SequenceNumber {
+        self.seq
+    }
+
+    pub fn kind(&self) -> ChangeKind {
+        self.kind
+    }
+
+    pub fn entry_id(&self) -> &str {
+        &self.entry_id
+    }
+
+    /// UTC timestamp of the change, as `YYYY-MM-DD HH:MM:SS`
+    pub fn created(&self) -> &str {
+        &self.created
+    }
+}
+
+/// Append a change, inside the transaction of the mutation
+pub(crate) fn record(conn: &Connection, now: &str, kind: ChangeKind, entry_id: &str) -> AppResult<()> {
+    conn.execute(
+        "INSERT INTO oplog (operation, entry_id, created) VALUES (?1, ?2, ?3)",
+        params![kind.as_str(), entry_id, now])
+        .map_err(|e| AppError::from_error(e, &format!("recording {} of {} in oplog", kind.as_str(), entry_id)))?;
+    Ok(())
+}
+
+/// Changes after `since`, oldest first, at most `limit` of them. Unknown operations
+/// are filtered out by the query, so that they do not count against `limit`.
+pub(crate) fn read_changes(conn: &Connection, since: SequenceNumber, limit: usize) -> AppResult<Vec<Change>> {
+    let since = i64::try_from(since.0).map_err(|_| AppError::new_custom(
+        AppCustomErrorKind::RepositoryMetadata, &format!("sequence number {} is out of range", since)))?;
+    let operations: Vec<String> = ChangeKind::ALL.iter().map(|kind| format!("'{}'", kind.as_str())).collect();
+    let sql = format!(
+        "SELECT seq, operation, entry_id, created FROM oplog
+         WHERE seq > ?1 AND operation IN ({}) ORDER BY seq LIMIT ?2",
+        operations.join(", "));
+    let mut stmt = conn
+        .prepare(&sql)
+        .map_err(|e| AppError::from_error(e, "preparing change feed query"))?;
+    let rows: Vec<(i64, String, String, String)> = stmt
+        .query_map(params![since, i64::try_from(limit).unwrap_or(i64::MAX)],
+                   |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))
+        .and_then(|rows| rows.collect())
+        .map_err(|e| AppError::from_error(e, "reading change feed"))?;
+    Ok(rows.into_iter()
+        .filter_map(|(seq, operation, entry_id, created)| {
+            let kind = ChangeKind::parse(&operation)?;
+            Some(Change { seq: SequenceNumber(seq as u64), kind, entry_id, created })
+        })
+        .collect())
+}
+
+/// Live feed of changes, delivered by a background thread polling the oplog on its own
+/// connection, so changes committed by other processes are received too. A read error
+/// is delivered once and ends the subscription. Dropping it wakes and stops the thread.
+pub struct Subscription {
+    receiver: Receiver<AppResult<Change>>,
+    // dropping the sender disconnects the channel the thread waits on between polls
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Subscription {
+    /// Wait up to `timeout` for the next change. `None` means no change arrived in time,
+    /// or the subscription ended.
+    pub fn next_timeout(&self, timeout: Duration) -> Option<AppResult<Change>> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(change) => Some(change),
+            Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => None,
+        }
+    }
+
+    /// Underlying channel, to block on or iterate
+    pub fn receiver(&self) -> &Receiver<AppResult<Change>> {
+        &self.receiver
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn poll_changes(database: RepositoryDB, mut since: SequenceNumber, poll_interval: Duration,
+                sender: Sender<AppResult<Change>>, stop: Receiver<()>) {
+    loop {
+        match read_changes(database.connection(), since, SUBSCRIPTION_BATCH) {
+            Ok(batch) => {
+                let full = batch.len() == SUBSCRIPTION_BATCH;
+                for change in batch {
+                    since = change.seq;
+                    if sender.send(Ok(change)).is_err() {
+                        return;
+                    }
+                }
+                if full {
+                    continue;
+                }
+            }
+            Err(err) => {
+                let _ = sender.send(Err(err));
+                return;
+            }
+        }
+        if stop.recv_timeout(poll_interval) != Err(RecvTimeoutError::Timeout) {
+            return;
+        }
+    }
+}
+
+impl Repository {
+    /// Subscribe to the changes after `since`, checking for new ones every
+    /// `poll_interval`
+    pub fn subscribe(&self, since: SequenceNumber, poll_interval: Duration) -> AppResult<Subscription> {
+        let database = RepositoryDB::new(self.path())?;
+        let (sender, receiver) = mpsc::channel();
+        let (stop, thread_stop) = mpsc::channel();
+        let handle = thread::Builder::new()
+            .name("afilia-subscription".to_string())
+            .spawn(move || poll_changes(database, since, poll_interval, sender, thread_stop))
+            .map_err(|e| AppError::from_error(e, "starting subscription thread"))?;
+        Ok(Subscription { receiver, stop: Some(stop), handle: Some(handle) })
+    }
+
+    /// Catalog mutations after `since`, in order, at most `limit` of them. Pass the
+    /// sequence number of the last change received to get the following ones.
+    pub fn changes(&self, since: SequenceNumber, limit: usize) -> AppResult<Vec<Change>> {
+        read_changes(self.database().connection(), since, limit)
+    }
+}
@@ -0,0 +1,292 @@
+//! Checksum lists of the blob tree, in the layouts read by the usual `*sum -c` tools,
+//! `shasum -c` and hashdeep, so the storage can be audited without afilia itself. Lists
+//! can be made of the BLAKE3 hashes or of any digest recorded alongside (e.g. `sha256`
+//! or `md5`), which older tools can check. Lists produced by those tools
+//! (and by hashdeep) can also be imported as provisional entries, to plan a migration
+//! from the catalog before any data is copied.
+use std::io::{BufRead, Write};
+use rusqlite::params;
+use uuid::Uuid;
+use crate::filesystem::changes::{self, ChangeKind};
+use crate::filesystem::digest::{digest_length, BLAKE3};
+use crate::filesystem::error::{AppCustomErrorKind, AppError, AppResult};
+use crate::filesystem::freeze;
+use crate::filesystem::hash_format::{from_hex, to_hex};
+use crate::filesystem::repository::Repository;
+
+const HASHDEEP_HEADER: &str = "%%%% HASHDEEP-1.0";
+
+/// Layout of an exported checksum list
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
+pub enum ChecksumFormat {
+    /// `<hash>  <path>` lines, as checked by `b3sum -c` or GNU `sha256sum -c`
+    Gnu,
+    /// `BLAKE3 (<path>) = <hash>` lines, as written by BSD `shasum --tag`
+    Bsd,
+    /// hashdeep and md5deep audit files: a header, then `<size>,<hash>,<path>` lines
+    Hashdeep,
+}
+
+/// A file known from an imported checksum list, but not stored yet
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProvisionalEntry {
+    id: Uuid,
+    path: String,
+    size: Option<u64>,
+    digests: Vec<(String, Vec<u8>)>,
+}
+
+impl ProvisionalEntry {
+    pub fn id(&self) -> &Uuid {
+        &self.id
+    }
+
+    /// Path of the file, as written in the checksum list
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Size in bytes, when the list provides it
+    pub fn size(&self) -> Option<u64> {
+        self.size
+    }
+
+    /// Recorded digest for an algorithm name such as `sha256`
+    pub fn digest(&self, algorithm: &str) -> Option<&[u8]> {
+        self.digests.iter()
+            .find(|(a, _)| a == algorithm)
+            .map(|(_, h)| h.as_slice())
+    }
+}
+
+fn list_error(line: usize, msg: &str) -> AppError {
+    AppError::new_custom(AppCustomErrorKind::ChecksumList, &format!("line {}: {}", line, msg))
+}
+
+/// Escape a path as the GNU tools do: when it holds a backslash or a line break, these
+/// are escaped and the whole line is prefixed with a backslash
+fn escape_path(path: &str) -> (&'static str, String) {
+    if !path.contains(['\\', '\n', '\r']) {
+        return ("", path.to_string());
+    }
+    let escaped = path.replace('\\', "\\\\").replace('\n', "\\n").replace('\r', "\\r");
+    ("\\", escaped)
+}
+
+fn unescape_path(path: &str) -> Option<String> {
+    let mut unescaped = String::with_capacity(path.len());
+    let mut chars = path.chars();
+    while let Some(c) = chars.next() {
+        unescaped.push(match c {
+            '\\' => match chars.next()? {
+                '\\' => '\\',
+                'n' => '\n',
+                'r' => '\r',
+                _ => return None,
+            },
+            c => c,
+        });
+    }
+    Some(unescaped)
+}
+
+/// Parse a `<hash>  <path>` or `<hash> *<path>` line
+fn parse_gnu_line(id: Uuid, line: &str, algorithm: &str) -> Option<ProvisionalEntry> {
+    let (escaped, line) = match line.strip_prefix('\\') {
+        Some(line) => (true, line),
+        None => (false, line),
+    };
+    let (hash, rest) = line.split_once(' ')?;
+    let path = rest.strip_prefix(' ').or_else(|| rest.strip_prefix('*'))?;
+    Some(ProvisionalEntry {
+        id,
+        path: if escaped { unescape_path(path)? } else { path.to_string() },
+        size: None,
+        digests: vec![(algorithm.to_string(), from_hex(hash)?)],
+    })
+}
+
+/// Parse a `SHA256 (<path>) = <hash>` line
+fn parse_bsd_line(id: Uuid, line: &str) -> Option<ProvisionalEntry> {
+    let (escaped, line) = match line.strip_prefix('\\') {
+        Some(line) => (true, line),
+        None => (false, line),
+    };
+    let (algorithm, rest) = line.split_once(" (")?;
+    let (path, hash) = rest.rsplit_once(") = ")?;
+    Some(ProvisionalEntry {
+        id,
+        path: if escaped { unescape_path(path)? } else { path.to_string() },
+        size: None,
+        digests: vec![(algorithm.to_lowercase(), from_hex(hash)?)],
+    })
+}
+
+/// Parse a hashdeep line, whose columns are given by the `%%%% size,...,filename` header
+fn parse_hashdeep_line(id: Uuid, line: &str, columns: &[String]) -> Option<ProvisionalEntry> {
+    let values: Vec<&str> = line.splitn(columns.len(), ',').collect();
+    if values.len() != columns.len() {
+        return None;
+    }
+    let mut entry = ProvisionalEntry { id, path: String::new(), size: None, digests: vec![] };
+    for (column, value) in columns.iter().zip(values) {
+        match column.as_str() {
+            "size" => entry.size = Some(value.parse().ok()?),
+            "filename" => entry.path = value.to_string(),
+            algorithm => entry.digests.push((algorithm.to_string(), from_hex(value)?)),
+        }
+    }
+    Some(entry)
+}
+
+fn parse_checksum_list<R: BufRead>(reader: R, algorithm: &str, new_id: impl Fn() -> Uuid) -> AppResult<Vec<ProvisionalEntry>> {
+    let mut entries = Vec::new();
+    let mut hashdeep: Option<Vec<String>> = None;
+    for (index, line) in reader.lines().enumerate() {
+        let number = index + 1;
+        let line = line.map_err(|e| AppError::from_error(e, &format!("reading checksum list line {}", number)))?;
+        let line = line.trim_end_matches('\r');
+        if line.is_empty() || line.starts_with("##") {
+            continue;
+        }
+        if line == HASHDEEP_HEADER {
+            hashdeep = Some(vec![]);
+            continue;
+        }
+        if let Some(columns) = line.strip_prefix("%%%% ") {
+            let columns: Vec<String> = columns.split(',').map(String::from).collect();
+            if columns.last().map(String::as_str) != Some("filename") {
+                return Err(list_error(number, "hashdeep header must end with a filename column"));
+            }
+            hashdeep = Some(columns);
+            continue;
+        }
+        let entry = match &hashdeep {
+            Some(columns) if columns.is_empty() => return Err(list_error(number, "missing hashdeep column header")),
+            Some(columns) => parse_hashdeep_line(new_id(), line, columns),
+            None => {
+                let id = new_id();
+                parse_gnu_line(id, line, algorithm).or_else(|| parse_bsd_line(id, line))
+            }
+        };
+        let entry = entry.ok_or_else(|| list_error(number, "unrecognized checksum line"))?;
+        for (algorithm, hash) in entry.digests.iter() {
+            match digest_length(algorithm) {
+                Some(length) if length != hash.len() => return Err(list_error(
+                    number, &format!("{} digest of {} bytes, expected {}", algorithm, hash.len(), length))),
+                _ => {}
+            }
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+impl Repository {
+    /// Write the `algorithm` digest of every catalog entry along with its storage
+    /// path, relative to the repository root. `blake3` lists the content hashes; other
+    /// algorithms list the recorded digests, and entries without one are left out.
+    /// Returns the number of entries written.
+    pub fn export_checksums<W: Write>(&self, writer: &mut W, format: ChecksumFormat, algorithm: &str) -> AppResult<usize> {
+        let mut stmt = self.database().connection()
+            .prepare("SELECT d.hash, c.storage_path, c.size FROM main_catalog c
+                      JOIN (SELECT id AS entry_id, ?2 AS algorithm, hash FROM main_catalog
+                            UNION ALL
+                            SELECT entry_id, algorithm, hash FROM entry_digest) d ON d.entry_id = c.id
+                      WHERE d.algorithm = ?1
+                      ORDER BY c.storage_path")
+            .map_err(|e| AppError::from_error(e, "preparing checksum export"))?;
+        let rows = stmt.query_map(params![algorithm, BLAKE3], |row| {
+            Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, String>(1)?, row.get::<_, Option<i64>>(2)?))
+        }).map_err(|e| AppError::from_error(e, "querying catalog"))?;
+        if format == ChecksumFormat::Hashdeep {
+            write!(writer, "{}\n%%%% size,{},filename\n## Written by afilia\n##\n", HASHDEEP_HEADER, algorithm)
+                .map_err(|e| AppError::from_error(e, "writing checksum list"))?;
+        }
+        let mut count = 0;
+        for row in rows {
+            let (hash, path, size) = row.map_err(|e| AppError::from_error(e, "reading catalog entry"))?;
+            let line = match format {
+                ChecksumFormat::Gnu => {
+                    let (prefix, path) = escape_path(&path);
+                    format!("{}{}  {}", prefix, to_hex(&hash), path)
+                }
+                ChecksumFormat::Bsd => {
+                    let (prefix, path) = escape_path(&path);
+                    format!("{}{} ({}) = {}", prefix, algorithm.to_uppercase(), path, to_hex(&hash))
+                }
+                ChecksumFormat::Hashdeep => {
+                    if path.contains(['\n', '\r']) {
+                        return Err(AppError::new_custom(
+                            AppCustomErrorKind::ChecksumList,
+                            &format!("hashdeep cannot list {:?}, which holds a line break", path)));
+                    }
+                    format!("{},{},{}", size.unwrap_or(0), to_hex(&hash), path)
+                }
+            };
+            writeln!(writer, "{}", line)
+                .map_err(|e| AppError::from_error(e, "writing checksum list"))?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Record the files of a checksum list as provisional entries. GNU and BSD style
+    /// lists are accepted, as well as hashdeep files. `algorithm` names the digest of
+    /// GNU style lines (e.g. `sha256`), which do not state it. The whole list is
+    /// rejected if any line cannot be parsed, or holds a digest of the wrong length for
+    /// its algorithm. Returns the number of entries created.
+    pub fn import_checksums<R: BufRead>(&self, reader: R, algorithm: &str) -> AppResult<usize> {
+        freeze::ensure_writable(self.database().connection())?;
+        let entries = parse_checksum_list(reader, algorithm, || self.new_id())?;
+        let now = self.timestamp()?;
+        let tx = self.database().connection().unchecked_transaction()
+            .map_err(|e| AppError::from_error(e, "starting checksum import"))?;
+        for entry in entries.iter() {
+            tx.execute(
+                "INSERT INTO provisional_entry (id, path, size, created, modified) VALUES (?1, ?2, ?3, ?4, ?4)",
+                params![entry.id.to_string(), entry.path, entry.size, now])
+                .map_err(|e| AppError::from_error(e, &format!("recording {}", entry.path)))?;
+            for (algorithm, hash) in entry.digests.iter() {
+                tx.execute(
+                    "INSERT INTO provisional_digest (entry_id, algorithm, hash) VALUES (?1, ?2, ?3)",
+                    params![entry.id.to_string(), algorithm, hash])
+                    .map_err(|e| AppError::from_error(e, &format!("recording {} digest of {}", algorithm, entry.path)))?;
+            }
+            changes::record(&tx, &now, ChangeKind::ProvisionalAdded, &entry.id.to_string())?;
+        }
+        tx.commit().map_err(|e| AppError::from_error(e, "committing checksum import"))?;
+        self.telemetry().event("import_checksums", &[("algorithm", algorithm), ("entries", &entries.len().to_string())]);
+        self.telemetry().counter("afilia_provisional_entries_imported_total", entries.len() as u64);
+        Ok(entries.len())
+    }
+
+    /// All provisional entries, ordered by path
+    pub fn provisional_entries(&self) -> AppResult<Vec<ProvisionalEntry>> {
+        let conn = self.database().connection();
+        let mut stmt = conn
+            .prepare("SELECT id, path, size FROM provisional_entry ORDER BY path")
+            .map_err(|e| AppError::from_error(e, "preparing provisional entries query"))?;
+        let mut digest_stmt = conn
+            .prepare("SELECT algorithm, hash FROM provisional_digest WHERE entry_id = ?1 ORDER BY algorithm")
+            .map_err(|e| AppError::from_error(e, "preparing provisional digests query"))?;
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get(1)?, row.get(2)?)))
+            .map_err(|e| AppError::from_error(e, "querying provisional entries"))?;
+        let mut entries = Vec::new();
+        for row in rows {
+            let (id, path, size) = row.map_err(|e| AppError::from_error(e, "reading provisional entry"))?;
+            let digests = digest_stmt.query_map([&id], |row| Ok((row.get(0)?, row.get(1)?)))
+                .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+                .map_err(|e| AppError::from_error(e, &format!("reading digests of {}", path)))?;
+            entries.push(ProvisionalEntry {
+                id: Uuid::parse_str(&id).map_err(|_| AppError::new_custom(
+                    AppCustomErrorKind::RepositoryMetadata, &format!("invalid entry id {}", id)))?,
+                path,
+                size,
+                digests,
+            });
+        }
+        Ok(entries)
+    }
+}
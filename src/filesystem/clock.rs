@@ -0,0 +1,82 @@
+//! Time source of a repository. Every timestamp written by the library is taken from
+//! the repository clock, so that expiry logic can be tested with a controlled clock.
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::filesystem::error::{AppError, AppResult};
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+/// The system wall clock
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock that only moves when told to
+#[derive(Debug)]
+pub struct TestClock {
+    now: Mutex<SystemTime>,
+}
+
+impl TestClock {
+    pub fn new(now: SystemTime) -> TestClock {
+        TestClock { now: Mutex::new(now) }
+    }
+
+    /// A clock stopped `secs` seconds after the Unix epoch
+    pub fn at_unix(secs: u64) -> TestClock {
+        TestClock::new(UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    pub fn set(&self, now: SystemTime) {
+        *self.now.lock().unwrap_or_else(|e| e.into_inner()) = now;
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        let mut now = self.now.lock().unwrap_or_else(|e| e.into_inner());
+        *now += duration;
+    }
+}
+
+impl Clock for TestClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Seconds since the Unix epoch. A clock set before the epoch is an error.
+pub(crate) fn unix_seconds(clock: &dyn Clock) -> AppResult<u64> {
+    clock.now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .map_err(|e| AppError::from_error(e, "reading clock"))
+}
+
+/// Proleptic Gregorian `(year, month, day)` of a count of days since the Unix epoch
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// UTC timestamp in the `YYYY-MM-DD HH:MM:SS` format of SQLite `CURRENT_TIMESTAMP`
+pub(crate) fn timestamp(clock: &dyn Clock) -> AppResult<String> {
+    let secs = unix_seconds(clock)?;
+    let (year, month, day) = civil_from_days(secs / 86_400);
+    let time = secs % 86_400;
+    Ok(format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+               year, month, day, time / 3600, time % 3600 / 60, time % 60))
+}
@@ -0,0 +1,314 @@
+//! Helpers looking into the content of entries, reading blobs as streams so large
+//! entries are never held in memory. The unified diff of text entries is the exception:
+//! both sides are read whole, up to a size set by the caller.
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use crate::filesystem::error::{AppError, AppResult};
+use crate::filesystem::repository::Repository;
+
+/// Width in bytes of the shingles compared by the similarity estimate
+const SHINGLE_WIDTH: usize = 16;
+/// One shingle in this many is kept, chosen by hash so that content shifted by an
+/// insertion keeps the same samples
+const SHINGLE_SAMPLING: u64 = 32;
+const ROLLING_BASE: u64 = 0x100_0000_01b3;
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Number of bytes per line of a hex dump
+const HEX_DUMP_WIDTH: usize = 16;
+/// Unchanged lines shown around each change of a unified diff
+const UNIFIED_CONTEXT: usize = 3;
+
+/// Beginning of the content of an entry, for display
+#[derive(Debug, Clone, PartialEq)]
+pub struct Preview {
+    text: String,
+    is_text: bool,
+    truncated: bool,
+}
+
+impl Preview {
+    /// Decoded text, or a hex dump of binary content
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Whether the content was decoded as UTF-8 text
+    pub fn is_text(&self) -> bool {
+        self.is_text
+    }
+
+    /// Whether the content goes on past the preview
+    pub fn truncated(&self) -> bool {
+        self.truncated
+    }
+}
+
+/// `offset  hex bytes  |ascii|` lines, as printed by `hexdump -C`
+fn hex_dump(bytes: &[u8]) -> String {
+    bytes.chunks(HEX_DUMP_WIDTH)
+        .enumerate()
+        .map(|(line, chunk)| {
+            let hex: Vec<String> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+            let ascii: String = chunk.iter()
+                .map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' })
+                .collect();
+            format!("{:08x}  {:<47}  |{}|\n", line * HEX_DUMP_WIDTH, hex.join(" "), ascii)
+        })
+        .collect()
+}
+
+/// UTF-8 text without control characters other than whitespace. A character cut by
+/// the end of a truncated preview is dropped.
+fn decode_text(bytes: &[u8], truncated: bool) -> Option<&str> {
+    let text = match std::str::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(err) if truncated && err.error_len().is_none() => std::str::from_utf8(&bytes[..err.valid_up_to()]).ok()?,
+        Err(_) => return None,
+    };
+    text.chars().all(|c| !c.is_control() || c.is_whitespace()).then_some(text)
+}
+
+/// Comparison of the content of two entries
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiffSummary {
+    identical: bool,
+    size_delta: i64,
+    similarity: f64,
+}
+
+impl DiffSummary {
+    pub fn identical(&self) -> bool {
+        self.identical
+    }
+
+    /// Size of the second entry minus size of the first, in bytes
+    pub fn size_delta(&self) -> i64 {
+        self.size_delta
+    }
+
+    /// Estimated share of content in common, from 0 to 1
+    pub fn similarity(&self) -> f64 {
+        self.similarity
+    }
+}
+
+/// A line of a diff, with its index in the old and the new text
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DiffLine {
+    Keep(usize, usize),
+    Delete(usize),
+    Insert(usize),
+}
+
+/// Shortest edit script turning `old` into `new`, by Myers' algorithm. The frontier of
+/// each step is kept for the backtrack, so memory grows with the square of the edits.
+fn diff_lines(old: &[&str], new: &[&str]) -> Vec<DiffLine> {
+    let (n, m) = (old.len() as isize, new.len() as isize);
+    let offset = n + m + 1;
+    let mut frontier = vec![0isize; 2 * offset as usize + 1];
+    let mut trace = Vec::new();
+    'search: for d in 0..=n + m {
+        trace.push(frontier[(offset - d) as usize..=(offset + d) as usize].to_vec());
+        for k in (-d..=d).step_by(2) {
+            let i = (offset + k) as usize;
+            let mut x = if k == -d || (k != d && frontier[i - 1] < frontier[i + 1]) {
+                frontier[i + 1]
+            } else {
+                frontier[i - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && old[x as usize] == new[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            frontier[i] = x;
+            if x >= n && y >= m {
+                break 'search;
+            }
+        }
+    }
+
+    let mut script = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for (d, previous) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        // `previous` holds the frontier of step d - 1, indexed from diagonal -d
+        let at = |k: isize| previous[(k + d) as usize];
+        let k = x - y;
+        let previous_k = if k == -d || (k != d && at(k - 1) < at(k + 1)) { k + 1 } else { k - 1 };
+        let previous_x = if d == 0 { 0 } else { at(previous_k) };
+        let previous_y = previous_x - previous_k;
+        while x > previous_x.max(0) && y > previous_y.max(0) {
+            x -= 1;
+            y -= 1;
+            script.push(DiffLine::Keep(x as usize, y as usize));
+        }
+        if d > 0 {
+            if x == previous_x {
+                script.push(DiffLine::Insert(previous_y as usize));
+            } else {
+                script.push(DiffLine::Delete(previous_x as usize));
+            }
+        }
+        x = previous_x;
+        y = previous_y;
+    }
+    script.reverse();
+    script
+}
+
+/// `diff -u` output of two texts, empty when they are equal
+fn unified(old_name: &str, old: &str, new_name: &str, new: &str) -> String {
+    let old_lines: Vec<&str> = old.split_inclusive('\n').collect();
+    let new_lines: Vec<&str> = new.split_inclusive('\n').collect();
+    let script = diff_lines(&old_lines, &new_lines);
+    let changes: Vec<usize> = script.iter().enumerate()
+        .filter(|(_, line)| !matches!(line, DiffLine::Keep(..)))
+        .map(|(index, _)| index)
+        .collect();
+    let Some(&first) = changes.first() else {
+        return String::new();
+    };
+
+    // group changes closer than twice the context into hunks of script indexes
+    let mut hunks = vec![(first, first)];
+    for &change in &changes[1..] {
+        let last = hunks.last_mut().expect("hunks start with the first change");
+        if change - last.1 <= 2 * UNIFIED_CONTEXT + 1 {
+            last.1 = change;
+        } else {
+            hunks.push((change, change));
+        }
+    }
+
+    let mut text = format!("--- {}\n+++ {}\n", old_name, new_name);
+    let push_line = |text: &mut String, mark: char, line: &str| {
+        text.push(mark);
+        text.push_str(line);
+        if !line.ends_with('\n') {
+            text.push_str("\n\\ No newline at end of file\n");
+        }
+    };
+    for (start, end) in hunks {
+        let lines = &script[start.saturating_sub(UNIFIED_CONTEXT)..(end + UNIFIED_CONTEXT + 1).min(script.len())];
+        // position of the hunk in each text, counting the lines before its first line
+        let (old_start, new_start) = script[..start.saturating_sub(UNIFIED_CONTEXT)].iter()
+            .fold((0, 0), |(o, n), line| match line {
+                DiffLine::Keep(..) => (o + 1, n + 1),
+                DiffLine::Delete(_) => (o + 1, n),
+                DiffLine::Insert(_) => (o, n + 1),
+            });
+        let old_count = lines.iter().filter(|line| !matches!(line, DiffLine::Insert(_))).count();
+        let new_count = lines.iter().filter(|line| !matches!(line, DiffLine::Delete(_))).count();
+        let range = |start: usize, count: usize| format!("{},{}", if count == 0 { start } else { start + 1 }, count);
+        text.push_str(&format!("@@ -{} +{} @@\n", range(old_start, old_count), range(new_start, new_count)));
+        for line in lines {
+            match *line {
+                DiffLine::Keep(o, _) => push_line(&mut text, ' ', old_lines[o]),
+                DiffLine::Delete(o) => push_line(&mut text, '-', old_lines[o]),
+                DiffLine::Insert(n) => push_line(&mut text, '+', new_lines[n]),
+            }
+        }
+    }
+    text
+}
+
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Size, BLAKE3 hash and sampled shingle hashes of a blob
+fn fingerprint(path: &Path) -> AppResult<(u64, blake3::Hash, HashSet<u64>)> {
+    let mut file = File::open(path).map_err(|e| AppError::from_error(e, &format!("opening {}", path.display())))?;
+    let mut hasher = blake3::Hasher::new();
+    let mut samples = HashSet::new();
+    let mut window = [0u8; SHINGLE_WIDTH];
+    let outgoing_factor = (0..SHINGLE_WIDTH).fold(1u64, |f, _| f.wrapping_mul(ROLLING_BASE));
+    let mut rolling = 0u64;
+    let mut size = 0u64;
+    let mut buffer = vec![0u8; READ_BUFFER_SIZE];
+    loop {
+        let read = file.read(&mut buffer)
+            .map_err(|e| AppError::from_error(e, &format!("reading {}", path.display())))?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        for &byte in &buffer[..read] {
+            let slot = size as usize % SHINGLE_WIDTH;
+            rolling = rolling.wrapping_mul(ROLLING_BASE).wrapping_add(byte as u64)
+                .wrapping_sub((window[slot] as u64).wrapping_mul(outgoing_factor));
+            window[slot] = byte;
+            size += 1;
+            let shingle = mix(rolling);
+            if size >= SHINGLE_WIDTH as u64 && shingle % SHINGLE_SAMPLING == 0 {
+                samples.insert(shingle);
+            }
+        }
+    }
+    Ok((size, hasher.finalize(), samples))
+}
+
+impl Repository {
+    /// Read at most `max_bytes` from the start of an entry, as text when it decodes as
+    /// such and as a hex dump otherwise
+    pub fn preview(&self, id: &str, max_bytes: usize) -> AppResult<Preview> {
+        let path = self.blob_path(id)?;
+        let file = File::open(&path).map_err(|e| AppError::from_error(e, &format!("opening {}", path.display())))?;
+        let mut bytes = Vec::with_capacity(max_bytes.min(READ_BUFFER_SIZE) + 1);
+        file.take((max_bytes as u64).saturating_add(1)).read_to_end(&mut bytes)
+            .map_err(|e| AppError::from_error(e, &format!("reading {}", path.display())))?;
+        let truncated = bytes.len() > max_bytes;
+        bytes.truncate(max_bytes);
+        Ok(match decode_text(&bytes, truncated) {
+            Some(text) => Preview { text: text.to_string(), is_text: true, truncated },
+            None => Preview { text: hex_dump(&bytes), is_text: false, truncated },
+        })
+    }
+
+    /// Compare the content of two entries: whether they are identical, how their sizes
+    /// differ and an estimate of their similarity, the Jaccard index of content-defined
+    /// samples of their shingles.
+    pub fn diff_entries(&self, a: &str, b: &str) -> AppResult<DiffSummary> {
+        let (size_a, hash_a, samples_a) = fingerprint(&self.blob_path(a)?)?;
+        let (size_b, hash_b, samples_b) = fingerprint(&self.blob_path(b)?)?;
+        let identical = hash_a == hash_b;
+        let union = samples_a.union(&samples_b).count();
+        let similarity = if identical {
+            1.0
+        } else if union == 0 {
+            0.0
+        } else {
+            samples_a.intersection(&samples_b).count() as f64 / union as f64
+        };
+        Ok(DiffSummary { identical, size_delta: size_b as i64 - size_a as i64, similarity })
+    }
+
+    /// Unified diff of two text entries, with their ids as file names, empty when they
+    /// have the same content. `None` when either entry is binary or larger than
+    /// `max_bytes`.
+    pub fn unified_diff(&self, a: &str, b: &str, max_bytes: usize) -> AppResult<Option<String>> {
+        let (Some(old), Some(new)) = (self.read_text(a, max_bytes)?, self.read_text(b, max_bytes)?) else {
+            return Ok(None);
+        };
+        Ok(Some(unified(a, &old, b, &new)))
+    }
+
+    /// Whole content of an entry as text, unless binary or larger than `max_bytes`
+    fn read_text(&self, id: &str, max_bytes: usize) -> AppResult<Option<String>> {
+        let path = self.blob_path(id)?;
+        let file = File::open(&path).map_err(|e| AppError::from_error(e, &format!("opening {}", path.display())))?;
+        let mut bytes = Vec::new();
+        file.take((max_bytes as u64).saturating_add(1)).read_to_end(&mut bytes)
+            .map_err(|e| AppError::from_error(e, &format!("reading {}", path.display())))?;
+        if bytes.len() > max_bytes {
+            return Ok(None);
+        }
+        Ok(decode_text(&bytes, false).map(String::from))
+    }
+}
@@ -0,0 +1,108 @@
+//! Additional digests of catalog entries. The BLAKE3 hash in `main_catalog` remains the
+//! content address; other digests (e.g. `sha256` or `md5` known from legacy systems)
+//! are recorded alongside so entries can be found by whatever hash others refer to.
+use rusqlite::{params, OptionalExtension};
+use crate::filesystem::changes::{self, ChangeKind};
+use crate::filesystem::error::{AppCustomErrorKind, AppError, AppResult};
+use crate::filesystem::freeze;
+use crate::filesystem::hash_format::parse_multibase;
+use crate::filesystem::repository::Repository;
+
+/// Name of the algorithm of the catalog content hash
+pub const BLAKE3: &str = "blake3";
+
+/// Digest lengths, in bytes, of the algorithms commonly found in checksum lists
+const DIGEST_LENGTHS: [(&str, usize); 5] = [
+    (BLAKE3, 32),
+    ("sha256", 32),
+    ("sha512", 64),
+    ("sha1", 20),
+    ("md5", 16),
+];
+
+/// Length of the digests of `algorithm`, if it is a known one
+pub(crate) fn digest_length(algorithm: &str) -> Option<usize> {
+    DIGEST_LENGTHS.iter().find(|(name, _)| *name == algorithm).map(|(_, length)| *length)
+}
+
+impl Repository {
+    /// Record the `algorithm` digest of an entry. Digests are system metadata: once
+    /// recorded, a digest cannot be replaced, and recording it again is a no-op. The
+    /// BLAKE3 hash is part of the entry itself and cannot be recorded this way. A digest
+    /// of a known algorithm must have its length.
+    pub fn record_digest(&self, id: &str, algorithm: &str, hash: &[u8]) -> AppResult<()> {
+        if algorithm == BLAKE3 {
+            return Err(AppError::new_custom(
+                AppCustomErrorKind::RepositoryMetadata,
+                "the blake3 hash of an entry is immutable"));
+        }
+        if let Some(length) = digest_length(algorithm).filter(|length| *length != hash.len()) {
+            return Err(AppError::new_custom(
+                AppCustomErrorKind::InvalidInput,
+                &format!("{} digest of {} bytes, expected {}", algorithm, hash.len(), length)));
+        }
+        freeze::ensure_writable(self.database().connection())?;
+        let immutable = self.is_immutable(id)?;
+        let recorded: Option<Vec<u8>> = self.database().connection()
+            .query_row(
+                "SELECT hash FROM entry_digest WHERE entry_id = ?1 AND algorithm = ?2",
+                [id, algorithm],
+                |row| row.get(0))
+            .optional()
+            .map_err(|e| AppError::from_error(e, &format!("looking up {} digest of {}", algorithm, id)))?;
+        match recorded {
+            Some(recorded) if recorded == hash => return Ok(()),
+            Some(_) if immutable => return Err(AppError::new_custom(
+                AppCustomErrorKind::ImmutableEntry,
+                &format!("entry {} is immutable, its {} digest cannot be replaced", id, algorithm))),
+            Some(_) => return Err(AppError::new_custom(
+                AppCustomErrorKind::RepositoryMetadata,
+                &format!("the {} digest of entry {} is already recorded", algorithm, id))),
+            None => {}
+        }
+        let now = self.timestamp()?;
+        let tx = self.database().connection().unchecked_transaction()
+            .map_err(|e| AppError::from_error(e, "starting digest recording"))?;
+        tx.execute(
+            "INSERT INTO entry_digest (entry_id, algorithm, hash, created) VALUES (?1, ?2, ?3, ?4)",
+            params![id, algorithm, hash, now])
+            .map_err(|e| AppError::from_error(e, &format!("recording {} digest of {}", algorithm, id)))?;
+        changes::record(&tx, &now, ChangeKind::DigestRecorded, id)?;
+        tx.commit().map_err(|e| AppError::from_error(e, "committing digest recording"))
+    }
+
+    /// All digests of an entry as `(algorithm, hash)` pairs, BLAKE3 first
+    pub fn digests(&self, id: &str) -> AppResult<Vec<(String, Vec<u8>)>> {
+        let mut stmt = self.database().connection()
+            .prepare("SELECT algorithm, hash FROM (
+                          SELECT ?2 AS algorithm, hash FROM main_catalog WHERE id = ?1
+                          UNION ALL
+                          SELECT algorithm, hash FROM entry_digest WHERE entry_id = ?1)
+                      ORDER BY algorithm <> ?2, algorithm")
+            .map_err(|e| AppError::from_error(e, "preparing digests query"))?;
+        stmt.query_map(params![id, BLAKE3], |row| Ok((row.get(0)?, row.get(1)?)))
+            .and_then(|rows| rows.collect())
+            .map_err(|e| AppError::from_error(e, &format!("reading digests of {}", id)))
+    }
+
+    /// Ids of the entries with the given digest
+    pub fn find_by_digest(&self, algorithm: &str, hash: &[u8]) -> AppResult<Vec<String>> {
+        let mut stmt = self.database().connection()
+            .prepare("SELECT id FROM main_catalog WHERE ?1 = ?3 AND hash = ?2
+                      UNION
+                      SELECT entry_id FROM entry_digest WHERE algorithm = ?1 AND hash = ?2
+                      ORDER BY 1")
+            .map_err(|e| AppError::from_error(e, "preparing digest lookup"))?;
+        stmt.query_map(params![algorithm, hash, BLAKE3], |row| row.get(0))
+            .and_then(|rows| rows.collect())
+            .map_err(|e| AppError::from_error(e, &format!("looking up {} digest", algorithm)))
+    }
+
+    /// Entries matching a multibase-encoded multihash, as used by multiformats
+    /// tooling. The algorithm is taken from the multihash.
+    pub fn find_by_multihash(&self, multihash: &str) -> AppResult<Vec<String>> {
+        let (algorithm, hash) = parse_multibase(multihash).ok_or_else(|| AppError::new_custom(
+            AppCustomErrorKind::RepositoryMetadata, &format!("invalid multihash {}", multihash)))?;
+        self.find_by_digest(&algorithm, &hash)
+    }
+}
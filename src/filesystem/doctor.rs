@@ -0,0 +1,167 @@
+//! Environment self-test, run before trusting a repository with data. Each check tells
+//! whether the database, filesystem, clock and locking behave as the library expects,
+//! with a detail message saying what to fix. Free space, reflink and extended attribute
+//! support need platform APIs outside the standard library; they are listed in the
+//! report as not checked rather than left out.
+use std::fs;
+use std::time::Duration;
+use crate::filesystem::clock::{timestamp, unix_seconds};
+use crate::filesystem::error::{AppError, AppResult};
+use crate::filesystem::repository::{Repository, SIGN_FILE_NAME};
+
+/// SQLite 3.24 brought the upsert syntax used throughout the library
+const MIN_SQLITE_VERSION: i32 = 3_024_000;
+/// 2020-01-01, anything earlier means the clock was never set
+const MIN_CLOCK_SECS: u64 = 1_577_836_800;
+const PROBE_FILE_NAME: &str = ".afilia_doctor";
+const PROBE_LEASE_TTL: Duration = Duration::from_secs(5);
+/// Checks this build cannot run
+const UNSUPPORTED_CHECKS: [(&str, &str); 3] = [
+    ("free_space", "free space"),
+    ("reflinks", "reflink support"),
+    ("xattrs", "extended attribute support"),
+];
+
+/// Outcome of a single check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CheckStatus {
+    Pass,
+    /// Works, but something deserves attention
+    Warn,
+    /// The repository should not be trusted with data until fixed
+    Fail,
+    /// Not checked by this build
+    Unsupported,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DoctorCheck {
+    name: &'static str,
+    status: CheckStatus,
+    detail: String,
+}
+
+impl DoctorCheck {
+    pub fn name(&self) -> &str {
+        self.name
+    }
+
+    pub fn status(&self) -> CheckStatus {
+        self.status
+    }
+
+    pub fn detail(&self) -> &str {
+        &self.detail
+    }
+}
+
+/// Results of all the checks, in the order they ran
+#[derive(Debug, Clone, PartialEq)]
+pub struct DoctorReport {
+    checks: Vec<DoctorCheck>,
+}
+
+impl DoctorReport {
+    pub fn checks(&self) -> &[DoctorCheck] {
+        &self.checks
+    }
+
+    /// Whether no check failed
+    pub fn is_healthy(&self) -> bool {
+        self.checks.iter().all(|c| c.status != CheckStatus::Fail)
+    }
+}
+
+fn check(name: &'static str, status: CheckStatus, detail: String) -> DoctorCheck {
+    DoctorCheck { name, status, detail }
+}
+
+impl Repository {
+    /// Run the environment checks. Only a failure to run them is an error; failed
+    /// checks are reported.
+    pub fn doctor(&self) -> AppResult<DoctorReport> {
+        let mut checks = vec![
+            self.check_sqlite_version(),
+            self.check_journal_mode()?,
+            self.check_permissions(),
+            self.check_hardlinks(),
+            self.check_clock()?,
+            self.check_lease(),
+        ];
+        checks.extend(UNSUPPORTED_CHECKS.iter().map(|(name, what)| check(name, CheckStatus::Unsupported,
+            format!("{} is not checked, it needs platform APIs outside the standard library", what))));
+        Ok(DoctorReport { checks })
+    }
+
+    fn check_sqlite_version(&self) -> DoctorCheck {
+        if rusqlite::version_number() >= MIN_SQLITE_VERSION {
+            check("sqlite_version", CheckStatus::Pass, format!("SQLite {}", rusqlite::version()))
+        } else {
+            check("sqlite_version", CheckStatus::Fail, format!("SQLite {} is older than 3.24", rusqlite::version()))
+        }
+    }
+
+    fn check_journal_mode(&self) -> AppResult<DoctorCheck> {
+        let mode: String = self.database().connection()
+            .query_row("PRAGMA journal_mode", [], |row| row.get(0))
+            .map_err(|e| AppError::from_error(e, "reading journal mode"))?;
+        Ok(if mode.eq_ignore_ascii_case("wal") {
+            check("journal_mode", CheckStatus::Pass, "WAL".to_string())
+        } else {
+            check("journal_mode", CheckStatus::Warn,
+                  format!("journal mode is {}, readers are not isolated from writers; WAL is not supported \
+                           on network filesystems", mode))
+        })
+    }
+
+    fn check_permissions(&self) -> DoctorCheck {
+        let probe = self.path().join(PROBE_FILE_NAME);
+        let result = fs::read(self.path().join(SIGN_FILE_NAME))
+            .and_then(|_| fs::write(&probe, b"probe"))
+            .and_then(|_| fs::read(&probe))
+            .and_then(|_| fs::remove_file(&probe));
+        match result {
+            Ok(()) => check("permissions", CheckStatus::Pass, "repository is readable and writable".to_string()),
+            Err(err) => check("permissions", CheckStatus::Fail,
+                              format!("cannot read and write in {}: {}", self.path().display(), err)),
+        }
+    }
+
+    fn check_hardlinks(&self) -> DoctorCheck {
+        let probe = self.path().join(PROBE_FILE_NAME);
+        let link = self.path().join(format!("{}.link", PROBE_FILE_NAME));
+        let result = fs::write(&probe, b"probe").and_then(|_| fs::hard_link(&probe, &link));
+        let _ = fs::remove_file(&link);
+        let _ = fs::remove_file(&probe);
+        match result {
+            Ok(()) => check("hardlinks", CheckStatus::Pass, "filesystem supports hardlinks".to_string()),
+            Err(err) => check("hardlinks", CheckStatus::Warn,
+                              format!("filesystem does not support hardlinks, copies will be used: {}", err)),
+        }
+    }
+
+    /// The clock must be set, and not behind the timestamps already recorded
+    fn check_clock(&self) -> AppResult<DoctorCheck> {
+        if !unix_seconds(self.clock()).is_ok_and(|secs| secs >= MIN_CLOCK_SECS) {
+            return Ok(check("clock", CheckStatus::Fail, "clock is not set".to_string()));
+        }
+        let now = timestamp(self.clock())?;
+        let latest: Option<String> = self.database().connection()
+            .query_row("SELECT max(created) FROM (SELECT created FROM oplog UNION ALL SELECT created FROM audit_log)",
+                       [], |row| row.get(0))
+            .map_err(|e| AppError::from_error(e, "reading latest recorded timestamp"))?;
+        Ok(match latest {
+            Some(latest) if latest > now => check("clock", CheckStatus::Warn,
+                format!("clock reads {}, before the latest recorded change at {}", now, latest)),
+            _ => check("clock", CheckStatus::Pass, format!("clock reads {}", now)),
+        })
+    }
+
+    fn check_lease(&self) -> DoctorCheck {
+        match self.acquire_lease(PROBE_LEASE_TTL).and_then(|lease| self.release_lease(lease)) {
+            Ok(()) => check("writer_lease", CheckStatus::Pass, "writer lease can be acquired".to_string()),
+            Err(err) => check("writer_lease", CheckStatus::Warn, format!("writer lease unavailable: {}", err)),
+        }
+    }
+}
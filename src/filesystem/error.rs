@@ -4,17 +4,27 @@
 use std::clone::Clone;
 use std::{fmt, io, num};
 use serde_json;
-use rusqlite::Error;
 
 /// A specific custom `Result` for all functions
 pub type AppResult<T> = Result<T, AppError>;
 
 /// Error kind specific to an application error, different from standard errors.
 #[derive(Debug, PartialEq)]
+#[non_exhaustive]
 pub enum AppCustomErrorKind {
     RepositoryStructure,
     RepositoryMetadata,
     RepositorySign,
+    RepositoryLease,
+    ChecksumList,
+    Verify,
+    StorageUnit,
+    ImmutableEntry,
+    SafeCopy,
+    ExtensionSchema,
+    MetadataFile,
+    FrozenRepository,
+    InvalidInput,
     PhantomCloneError
 }
 
@@ -30,6 +40,36 @@ impl fmt::Display for AppCustomErrorKind {
             AppCustomErrorKind::RepositorySign => {
                 write!(f, "repository sign issue")
             }
+            AppCustomErrorKind::RepositoryLease => {
+                write!(f, "repository lease issue")
+            }
+            AppCustomErrorKind::ChecksumList => {
+                write!(f, "checksum list issue")
+            }
+            AppCustomErrorKind::Verify => {
+                write!(f, "repository verification issue")
+            }
+            AppCustomErrorKind::StorageUnit => {
+                write!(f, "storage unit issue")
+            }
+            AppCustomErrorKind::ImmutableEntry => {
+                write!(f, "immutable entry issue")
+            }
+            AppCustomErrorKind::SafeCopy => {
+                write!(f, "verified copy issue")
+            }
+            AppCustomErrorKind::ExtensionSchema => {
+                write!(f, "extension schema issue")
+            }
+            AppCustomErrorKind::MetadataFile => {
+                write!(f, "metadata file issue")
+            }
+            AppCustomErrorKind::FrozenRepository => {
+                write!(f, "frozen repository issue")
+            }
+            AppCustomErrorKind::InvalidInput => {
+                write!(f, "invalid input")
+            }
             AppCustomErrorKind::PhantomCloneError => {
                 write!(f, "no error")
             }
@@ -39,6 +79,7 @@ impl fmt::Display for AppCustomErrorKind {
 
 /// A specific error type combining all possible error types in the app.
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum InternalError {
     Io(io::Error),
     Parse(num::ParseIntError),
@@ -70,8 +111,8 @@ from_error!(rusqlite::Error, InternalError::Db);
 /// Custom error which will be used for all errors conversions and throughout the code.
 #[derive(Debug)]
 pub struct AppError {
-    pub error_kind: InternalError,
-    pub msg: String,
+    error_kind: InternalError,
+    msg: String,
 }
 
 impl AppError {
@@ -90,6 +131,65 @@ impl AppError {
             msg: msg.to_string(),
         }
     }
+
+    /// Underlying error
+    pub fn error_kind(&self) -> &InternalError {
+        &self.error_kind
+    }
+
+    /// Application specific kind, if this is a custom error
+    pub fn custom_kind(&self) -> Option<&AppCustomErrorKind> {
+        match &self.error_kind {
+            InternalError::Custom(kind) => Some(kind),
+            _ => None,
+        }
+    }
+
+    /// Context of the error
+    pub fn msg(&self) -> &str {
+        &self.msg
+    }
+
+    /// Process exit code for the class of this error. The mapping is stable across
+    /// releases, so scripts can branch on it; new classes only ever get new codes.
+    ///
+    /// | code | class                                                     |
+    /// |------|-----------------------------------------------------------|
+    /// | 1    | any other error                                           |
+    /// | 2    | reserved for command line usage errors                    |
+    /// | 3    | not a repository, or its sign does not match              |
+    /// | 4    | corruption: failed verification, corrupt database or copy |
+    /// | 5    | lock held: writer lease or busy database                  |
+    /// | 6    | quota: storage full                                       |
+    /// | 7    | refused on an immutable entry or a frozen repository      |
+    /// | 8    | invalid input: arguments, checksum list, metadata file,   |
+    /// |      | extension migration, JSON, UTF-8                          |
+    /// | 9    | I/O error                                                 |
+    /// | 10   | unknown entry or storage unit, or inconsistent metadata   |
+    pub fn exit_code(&self) -> i32 {
+        match &self.error_kind {
+            InternalError::Custom(kind) => match kind {
+                AppCustomErrorKind::RepositoryStructure | AppCustomErrorKind::RepositorySign => 3,
+                AppCustomErrorKind::Verify | AppCustomErrorKind::SafeCopy => 4,
+                AppCustomErrorKind::RepositoryLease => 5,
+                AppCustomErrorKind::ImmutableEntry | AppCustomErrorKind::FrozenRepository => 7,
+                AppCustomErrorKind::ChecksumList | AppCustomErrorKind::MetadataFile
+                | AppCustomErrorKind::ExtensionSchema | AppCustomErrorKind::InvalidInput => 8,
+                AppCustomErrorKind::RepositoryMetadata | AppCustomErrorKind::StorageUnit => 10,
+                AppCustomErrorKind::PhantomCloneError => 1,
+            },
+            InternalError::Io(err) if err.kind() == io::ErrorKind::StorageFull => 6,
+            InternalError::Io(_) => 9,
+            InternalError::Parse(_) | InternalError::Json(_) | InternalError::Utf8(_) => 8,
+            InternalError::Db(rusqlite::Error::SqliteFailure(err, _)) => match err.code {
+                rusqlite::ErrorCode::DatabaseCorrupt | rusqlite::ErrorCode::NotADatabase => 4,
+                rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked => 5,
+                rusqlite::ErrorCode::DiskFull => 6,
+                _ => 1,
+            },
+            InternalError::Db(_) | InternalError::SystemTime(_) => 1,
+        }
+    }
 }
 
 impl fmt::Display for AppError {
@@ -0,0 +1,117 @@
+//! Tables of embedding applications inside the repository database. An extension owns
+//! the objects whose name starts with `ext_<name>_` and evolves them through an
+//! append-only list of migrations, of which the repository records how many were
+//! applied. Extension tables live in the repository database, so they are carried
+//! along with it by any copy or backup.
+//!
+//! Migrations run under an authorizer which denies writing to the rows of any table
+//! outside the namespace, including `sqlite_sequence`, creating or dropping indexes,
+//! triggers and tables outside it, any temporary object, attaching databases and
+//! pragmas; schema changes are checked against the schema before and after each
+//! migration. As dropping an `AUTOINCREMENT` table deletes its `sqlite_sequence` row,
+//! such tables cannot be dropped once created. Triggers created on extension
+//! tables are the extension's responsibility once they fire, outside migrations.
+use std::collections::BTreeMap;
+use rusqlite::hooks::{AuthAction, AuthContext, Authorization};
+use rusqlite::{params, Connection, ErrorCode, OptionalExtension};
+use crate::filesystem::error::{AppCustomErrorKind, AppError, AppResult};
+use crate::filesystem::freeze;
+use crate::filesystem::repository::Repository;
+
+fn extension_error(msg: &str) -> AppError {
+    AppError::new_custom(AppCustomErrorKind::ExtensionSchema, msg)
+}
+
+/// Name and definition of every schema object
+fn schema_objects(conn: &Connection) -> AppResult<BTreeMap<String, Option<String>>> {
+    let mut stmt = conn.prepare("SELECT name, sql FROM sqlite_master")
+        .map_err(|e| AppError::from_error(e, "preparing schema query"))?;
+    stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .and_then(|rows| rows.collect())
+        .map_err(|e| AppError::from_error(e, "reading schema"))
+}
+
+/// Whether a migration of the extension owning `prefix` may perform an action
+fn authorize(prefix: &str, context: AuthContext) -> Authorization {
+    // creating and dropping objects writes to the schema table itself
+    let writable = |table: &str| table.starts_with(prefix) || table == "sqlite_master";
+    match context.action {
+        AuthAction::Insert { table_name }
+        | AuthAction::Update { table_name, .. }
+        | AuthAction::Delete { table_name } if !writable(table_name) => Authorization::Deny,
+        AuthAction::CreateIndex { table_name, .. }
+        | AuthAction::CreateTrigger { table_name, .. }
+        | AuthAction::DropIndex { table_name, .. }
+        | AuthAction::DropTable { table_name }
+        | AuthAction::DropTrigger { table_name, .. } if !table_name.starts_with(prefix) => Authorization::Deny,
+        AuthAction::CreateTempIndex { .. } | AuthAction::CreateTempTable { .. }
+        | AuthAction::CreateTempTrigger { .. } | AuthAction::CreateTempView { .. }
+        | AuthAction::DropTempIndex { .. } | AuthAction::DropTempTable { .. }
+        | AuthAction::DropTempTrigger { .. } | AuthAction::DropTempView { .. } => Authorization::Deny,
+        AuthAction::Attach { .. } | AuthAction::Detach { .. } | AuthAction::Pragma { .. } => Authorization::Deny,
+        _ => Authorization::Allow,
+    }
+}
+
+fn applied_version(conn: &Connection, name: &str) -> AppResult<usize> {
+    conn.query_row("SELECT version FROM extension_schema WHERE name = ?1", [name], |row| row.get::<_, i64>(0))
+        .optional()
+        .map(|version| version.unwrap_or(0) as usize)
+        .map_err(|e| AppError::from_error(e, &format!("reading schema version of extension {}", name)))
+}
+
+impl Repository {
+    /// Apply the migrations of extension `name` not applied yet, in order, and return
+    /// how many were. Migrations must only be appended to; each one may hold several
+    /// statements. A migration touching any object or row outside the `ext_<name>_`
+    /// namespace is rolled back, along with the rest of the batch.
+    pub fn register_extension_schema(&self, name: &str, migrations: &[&str]) -> AppResult<usize> {
+        if name.is_empty() || !name.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_') {
+            return Err(extension_error(&format!("invalid extension name {:?}", name)));
+        }
+        freeze::ensure_writable(self.database().connection())?;
+        let prefix = format!("ext_{}_", name);
+        let now = self.timestamp()?;
+        let tx = self.database().connection().unchecked_transaction()
+            .map_err(|e| AppError::from_error(e, "starting extension migration"))?;
+        let version = applied_version(&tx, name)?;
+        if version > migrations.len() {
+            return Err(extension_error(&format!(
+                "extension {} is at version {}, only {} migrations given", name, version, migrations.len())));
+        }
+        for (index, migration) in migrations.iter().enumerate().skip(version) {
+            let before = schema_objects(&tx)?;
+            let namespace = prefix.clone();
+            tx.authorizer(Some(move |context: AuthContext| authorize(&namespace, context)));
+            let applied = tx.execute_batch(migration);
+            tx.authorizer(None::<fn(AuthContext) -> Authorization>);
+            applied.map_err(|e| match e {
+                rusqlite::Error::SqliteFailure(err, _) if err.code == ErrorCode::AuthorizationForStatementDenied => extension_error(&format!(
+                    "migration {} of extension {} writes outside its namespace", index + 1, name)),
+                _ => AppError::from_error(e, &format!("applying migration {} of extension {}", index + 1, name)),
+            })?;
+            let after = schema_objects(&tx)?;
+            let foreign = before.iter()
+                .filter(|(object, sql)| after.get(*object) != Some(sql))
+                .chain(after.iter().filter(|(object, _)| !before.contains_key(*object)))
+                .map(|(object, _)| object)
+                .find(|object| !object.starts_with(&prefix));
+            if let Some(object) = foreign {
+                return Err(extension_error(&format!(
+                    "migration {} of extension {} changes {} outside its namespace", index + 1, name, object)));
+            }
+        }
+        tx.execute(
+            "INSERT INTO extension_schema (name, version, created, modified) VALUES (?1, ?2, ?3, ?3)
+             ON CONFLICT(name) DO UPDATE SET version = excluded.version, modified = excluded.modified",
+            params![name, migrations.len() as i64, now])
+            .map_err(|e| AppError::from_error(e, &format!("recording schema version of extension {}", name)))?;
+        tx.commit().map_err(|e| AppError::from_error(e, "committing extension migration"))?;
+        Ok(migrations.len() - version)
+    }
+
+    /// Number of migrations applied for extension `name`, 0 if it was never registered
+    pub fn extension_schema_version(&self, name: &str) -> AppResult<usize> {
+        applied_version(self.database().connection(), name)
+    }
+}
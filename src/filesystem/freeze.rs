@@ -0,0 +1,145 @@
+//! Archival freeze of a whole repository, for the end of a project. Freezing seals
+//! every storage unit, verifies every blob, makes the blobs and manifests read-only and
+//! records a passport of the final state as the freeze certificate. Until the repository
+//! is thawed, the library refuses any operation changing the catalog.
+//!
+//! On unix only the owner write bit is cleared and later restored, so that a thaw gives
+//! back the permissions the content had, group and other bits included.
+use std::fs;
+use std::io;
+use std::path::Path;
+use rusqlite::{params, Connection, OptionalExtension};
+use crate::filesystem::audit;
+use crate::filesystem::error::{AppCustomErrorKind, AppError, AppResult};
+use crate::filesystem::passport::Passport;
+use crate::filesystem::repository::Repository;
+use crate::filesystem::storage::MANIFEST_FILE_NAME;
+use crate::filesystem::verify::VerifyMode;
+
+const CERTIFICATE_PARAMETER: &str = "freeze_certificate";
+
+fn certificate(conn: &Connection) -> AppResult<Option<String>> {
+    conn.query_row("SELECT value FROM parameter WHERE key = ?1", [CERTIFICATE_PARAMETER], |row| row.get(0))
+        .optional()
+        .map_err(|e| AppError::from_error(e, "looking up freeze certificate"))
+}
+
+/// Fail if the repository is frozen
+pub(crate) fn ensure_writable(conn: &Connection) -> AppResult<()> {
+    match certificate(conn)? {
+        Some(_) => Err(AppError::new_custom(AppCustomErrorKind::FrozenRepository, "repository is frozen")),
+        None => Ok(()),
+    }
+}
+
+#[cfg(unix)]
+fn set_writable(path: &Path, writable: bool) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut permissions = fs::metadata(path)?.permissions();
+    let mode = permissions.mode();
+    permissions.set_mode(if writable { mode | 0o200 } else { mode & !0o200 });
+    fs::set_permissions(path, permissions)
+}
+
+#[cfg(not(unix))]
+#[allow(clippy::permissions_set_readonly_false)]
+fn set_writable(path: &Path, writable: bool) -> io::Result<()> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_readonly(!writable);
+    fs::set_permissions(path, permissions)
+}
+
+impl Repository {
+    pub fn is_frozen(&self) -> AppResult<bool> {
+        Ok(certificate(self.database().connection())?.is_some())
+    }
+
+    /// Passport recorded when the repository was frozen, if it is
+    pub fn freeze_certificate(&self) -> AppResult<Option<Passport>> {
+        certificate(self.database().connection())?
+            .map(|json| Passport::from_json(&json))
+            .transpose()
+    }
+
+    /// Freeze the repository and return its certificate. Everything is verified before
+    /// any unit is sealed, so nothing is recorded if a blob or a sealed unit fails.
+    pub fn freeze(&self) -> AppResult<Passport> {
+        ensure_writable(self.database().connection())?;
+        let report = self.verify(VerifyMode::Full)?;
+        let broken_units = self.verify_storage_units()?.iter().filter(|check| !check.is_intact()).count();
+        if !report.corrupted().is_empty() || !report.missing().is_empty() || broken_units > 0 {
+            return Err(AppError::new_custom(
+                AppCustomErrorKind::Verify,
+                &format!("cannot freeze: {} corrupted, {} missing entries, {} altered storage units",
+                         report.corrupted().len(), report.missing().len(), broken_units)));
+        }
+        for unit in self.storage_units()? {
+            if unit.sealed().is_none() {
+                self.seal_storage_unit(unit.id())?;
+            }
+        }
+        self.set_content_writable(false)?;
+        let passport = self.record_freeze().inspect_err(|_| {
+            let _ = self.set_content_writable(true);
+        })?;
+        self.telemetry().event("freeze", &[("catalog_root", passport.catalog_root())]);
+        Ok(passport)
+    }
+
+    /// Record the certificate of a repository about to be frozen
+    fn record_freeze(&self) -> AppResult<Passport> {
+        let passport = self.passport()?;
+        let now = self.timestamp()?;
+        let tx = self.database().connection().unchecked_transaction()
+            .map_err(|e| AppError::from_error(e, "starting freeze"))?;
+        tx.execute(
+            "INSERT INTO parameter (key, value, created, modified) VALUES (?1, ?2, ?3, ?3)",
+            params![CERTIFICATE_PARAMETER, passport.to_json()?, now])
+            .map_err(|e| AppError::from_error(e, "recording freeze certificate"))?;
+        audit::record(&tx, &now, "freeze", None, Some(&format!("catalog root {}", passport.catalog_root())))?;
+        tx.commit().map_err(|e| AppError::from_error(e, "committing freeze"))?;
+        Ok(passport)
+    }
+
+    /// Lift a freeze: blobs and manifests become writable again and the certificate is
+    /// dropped. Storage units stay sealed.
+    pub fn thaw(&self) -> AppResult<()> {
+        if !self.is_frozen()? {
+            return Err(AppError::new_custom(AppCustomErrorKind::FrozenRepository, "repository is not frozen"));
+        }
+        self.set_content_writable(true)?;
+        let now = self.timestamp()?;
+        let tx = self.database().connection().unchecked_transaction()
+            .map_err(|e| AppError::from_error(e, "starting thaw"))?;
+        tx.execute("DELETE FROM parameter WHERE key = ?1", [CERTIFICATE_PARAMETER])
+            .map_err(|e| AppError::from_error(e, "removing freeze certificate"))?;
+        audit::record(&tx, &now, "thaw", None, None)?;
+        tx.commit().map_err(|e| AppError::from_error(e, "committing thaw"))?;
+        self.telemetry().event("thaw", &[]);
+        Ok(())
+    }
+
+    /// Change the write permission of every blob and unit manifest. On failure, the files
+    /// already changed are changed back.
+    fn set_content_writable(&self, writable: bool) -> AppResult<()> {
+        let mut stmt = self.database().connection()
+            .prepare("SELECT storage_path FROM main_catalog ORDER BY storage_path")
+            .map_err(|e| AppError::from_error(e, "preparing blob listing"))?;
+        let mut paths: Vec<String> = stmt.query_map([], |row| row.get(0))
+            .and_then(|rows| rows.collect())
+            .map_err(|e| AppError::from_error(e, "reading catalog"))?;
+        for unit in self.storage_units()? {
+            paths.push(format!("{}/{}", unit.path(), MANIFEST_FILE_NAME));
+        }
+        let paths: Vec<_> = paths.iter().map(|path| self.path().join(path)).collect();
+        for (index, path) in paths.iter().enumerate() {
+            if let Err(err) = set_writable(path, writable) {
+                for changed in &paths[..index] {
+                    let _ = set_writable(changed, !writable);
+                }
+                return Err(AppError::from_error(err, &format!("changing permissions of {}", path.display())));
+            }
+        }
+        Ok(())
+    }
+}
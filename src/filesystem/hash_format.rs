@@ -0,0 +1,166 @@
+//! Text encodings of hashes. Besides plain hex and base32, hashes can be rendered as
+//! multibase-encoded multihashes (`b` prefix then base32 of `<code><length><digest>`),
+//! the form expected by IPFS and other multiformats tooling. Multihashes carry their
+//! algorithm, so they can be parsed back without knowing it.
+//!
+//! The format of a repository handle applies to the hashes it writes for people: audit
+//! details, passports, recovery kits and the roots of unit exports. Outputs read by
+//! other tools keep hex whatever the format: checksum lists (`sha256sum`, BSD tags and
+//! hashdeep), the blob names and `b3sum` manifests of storage units and unit exports,
+//! and the passport seal.
+use crate::filesystem::digest::{digest_length, BLAKE3};
+
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// Multihash codes of the algorithms afilia may record
+const MULTIHASH_CODES: [(&str, u64); 5] = [
+    (BLAKE3, 0x1e),
+    ("sha256", 0x12),
+    ("sha512", 0x13),
+    ("sha1", 0x11),
+    ("md5", 0xd5),
+];
+
+/// How hashes are rendered in outputs
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum HashFormat {
+    /// Lowercase hexadecimal
+    #[default]
+    Hex,
+    /// Lowercase RFC 4648 base32, without padding
+    Base32,
+    /// Multibase base32 (`b` prefix) of the multihash
+    Multibase,
+}
+
+impl HashFormat {
+    /// Render a digest of `algorithm`. Multibase falls back to hex for algorithms
+    /// without a known multihash code.
+    pub fn encode(&self, algorithm: &str, hash: &[u8]) -> String {
+        match self {
+            HashFormat::Hex => to_hex(hash),
+            HashFormat::Base32 => to_base32(hash),
+            HashFormat::Multibase => match multihash(algorithm, hash) {
+                Some(multihash) => format!("b{}", to_base32(&multihash)),
+                None => to_hex(hash),
+            },
+        }
+    }
+
+    /// Parse a digest rendered in this format. Multibase strings give back their
+    /// algorithm; the others do not know it.
+    pub fn decode(&self, text: &str) -> Option<(Option<String>, Vec<u8>)> {
+        match self {
+            HashFormat::Hex => from_hex(text).map(|hash| (None, hash)),
+            HashFormat::Base32 => from_base32(text).map(|hash| (None, hash)),
+            HashFormat::Multibase => parse_multibase(text).map(|(algorithm, hash)| (Some(algorithm), hash)),
+        }
+    }
+}
+
+/// Parse a digest of `algorithm` rendered in any of the formats, telling them apart by
+/// the digest length of the algorithm
+pub(crate) fn decode_any(algorithm: &str, text: &str) -> Option<Vec<u8>> {
+    let length = digest_length(algorithm)?;
+    [HashFormat::Hex, HashFormat::Base32, HashFormat::Multibase].iter()
+        .filter_map(|format| format.decode(text))
+        .find(|(decoded, hash)| hash.len() == length && decoded.as_deref().is_none_or(|a| a == algorithm))
+        .map(|(_, hash)| hash)
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub(crate) fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.is_empty() || hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| hex.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+        .collect()
+}
+
+fn to_base32(bytes: &[u8]) -> String {
+    let mut text = String::with_capacity(bytes.len().div_ceil(5) * 8);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for &byte in bytes {
+        buffer = (buffer << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            text.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        text.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    text
+}
+
+fn from_base32(text: &str) -> Option<Vec<u8>> {
+    if text.is_empty() {
+        return None;
+    }
+    let mut bytes = Vec::with_capacity(text.len() * 5 / 8);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for c in text.bytes() {
+        let value = BASE32_ALPHABET.iter().position(|&a| a == c.to_ascii_lowercase())? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+fn push_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+fn read_varint(bytes: &[u8]) -> Option<(u64, &[u8])> {
+    let mut value = 0u64;
+    for (i, &byte) in bytes.iter().enumerate().take(9) {
+        value |= ((byte & 0x7f) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, &bytes[i + 1..]));
+        }
+    }
+    None
+}
+
+fn multihash(algorithm: &str, hash: &[u8]) -> Option<Vec<u8>> {
+    let (_, code) = MULTIHASH_CODES.iter().find(|(name, _)| *name == algorithm)?;
+    let mut bytes = Vec::with_capacity(hash.len() + 4);
+    push_varint(&mut bytes, *code);
+    push_varint(&mut bytes, hash.len() as u64);
+    bytes.extend_from_slice(hash);
+    Some(bytes)
+}
+
+/// Decode a multibase multihash (base32 `b` or base16 `f` prefix) into its algorithm
+/// and digest
+pub fn parse_multibase(text: &str) -> Option<(String, Vec<u8>)> {
+    let bytes = match text.split_at_checked(1)? {
+        ("b", rest) | ("B", rest) => from_base32(rest)?,
+        ("f", rest) | ("F", rest) => from_hex(&rest.to_ascii_lowercase())?,
+        _ => return None,
+    };
+    let (code, rest) = read_varint(&bytes)?;
+    let (length, digest) = read_varint(rest)?;
+    if digest.len() as u64 != length {
+        return None;
+    }
+    let (algorithm, _) = MULTIHASH_CODES.iter().find(|(_, c)| *c == code)?;
+    Some((algorithm.to_string(), digest.to_vec()))
+}
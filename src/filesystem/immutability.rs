@@ -0,0 +1,58 @@
+//! Per-entry immutability. An immutable entry is a "golden master": operations that
+//! would destroy or rewrite what is recorded about it are refused by the library until
+//! the flag is cleared, which is itself audited.
+use rusqlite::{params, Connection, OptionalExtension};
+use crate::filesystem::audit;
+use crate::filesystem::changes::{self, ChangeKind};
+use crate::filesystem::error::{AppCustomErrorKind, AppError, AppResult};
+use crate::filesystem::freeze;
+use crate::filesystem::repository::Repository;
+
+fn immutable_flag(conn: &Connection, id: &str) -> AppResult<Option<bool>> {
+    conn.query_row("SELECT immutable FROM main_catalog WHERE id = ?1", [id], |row| row.get(0))
+        .optional()
+        .map_err(|e| AppError::from_error(e, &format!("looking up entry {}", id)))
+}
+
+fn unknown_entry(id: &str) -> AppError {
+    AppError::new_custom(AppCustomErrorKind::RepositoryMetadata, &format!("unknown entry {}", id))
+}
+
+/// Fail if there is no entry `id` in the catalog
+pub(crate) fn ensure_entry_exists(conn: &Connection, id: &str) -> AppResult<()> {
+    immutable_flag(conn, id)?.map(|_| ()).ok_or_else(|| unknown_entry(id))
+}
+
+/// Fail if an entry is flagged immutable. Unknown entries are left to the caller.
+pub(crate) fn ensure_mutable(conn: &Connection, id: &str) -> AppResult<()> {
+    match immutable_flag(conn, id)? {
+        Some(true) => Err(AppError::new_custom(
+            AppCustomErrorKind::ImmutableEntry, &format!("entry {} is immutable", id))),
+        _ => Ok(()),
+    }
+}
+
+impl Repository {
+    pub fn is_immutable(&self, id: &str) -> AppResult<bool> {
+        immutable_flag(self.database().connection(), id)?.ok_or_else(|| unknown_entry(id))
+    }
+
+    /// Flag or unflag an entry immutable
+    pub fn set_immutable(&self, id: &str, immutable: bool) -> AppResult<()> {
+        freeze::ensure_writable(self.database().connection())?;
+        if self.is_immutable(id)? == immutable {
+            return Ok(());
+        }
+        let now = self.timestamp()?;
+        let tx = self.database().connection().unchecked_transaction()
+            .map_err(|e| AppError::from_error(e, "starting immutability change"))?;
+        tx.execute(
+            "UPDATE main_catalog SET immutable = ?1, modified = ?3 WHERE id = ?2",
+            params![immutable, id, now])
+            .map_err(|e| AppError::from_error(e, &format!("changing immutability of {}", id)))?;
+        let action = if immutable { "set_immutable" } else { "clear_immutable" };
+        audit::record(&tx, &now, action, Some(id), None)?;
+        changes::record(&tx, &now, ChangeKind::ImmutabilityChanged, id)?;
+        tx.commit().map_err(|e| AppError::from_error(e, "committing immutability change"))
+    }
+}
@@ -0,0 +1,84 @@
+//! Leased-writer protocol. A single heartbeat row in the `lease` table tells which
+//! process is allowed to write to the repository. The holder must renew it before it
+//! expires; a crashed writer simply stops renewing and its lease lapses on its own.
+use std::time::Duration;
+use uuid::Uuid;
+use crate::filesystem::clock::unix_seconds;
+use crate::filesystem::error::{AppCustomErrorKind, AppError, AppResult};
+use crate::filesystem::repository::Repository;
+
+const WRITER_LEASE: &str = "writer";
+
+/// A writer lease held by this process
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lease {
+    holder: Uuid,
+    expires: u64,
+}
+
+impl Lease {
+    /// Unique identifier of the lease holder
+    pub fn holder(&self) -> &Uuid {
+        &self.holder
+    }
+
+    /// Expiration time, in seconds since the Unix epoch
+    pub fn expires(&self) -> u64 {
+        self.expires
+    }
+}
+
+/// Expiration of a lease taken at `now` for `ttl`
+fn expiry(now: u64, ttl: Duration) -> AppResult<u64> {
+    now.checked_add(ttl.as_secs())
+        .filter(|expires| i64::try_from(*expires).is_ok())
+        .ok_or_else(|| AppError::new_custom(
+            AppCustomErrorKind::RepositoryLease, &format!("lease duration {:?} is out of range", ttl)))
+}
+
+impl Repository {
+    /// Acquire the writer lease for `ttl`. Fails if another holder has a lease which
+    /// has not yet expired.
+    pub fn acquire_lease(&self, ttl: Duration) -> AppResult<Lease> {
+        let now = unix_seconds(self.clock())?;
+        let lease = Lease { holder: self.new_id(), expires: expiry(now, ttl)? };
+        let updates = self.database().execute(
+            "INSERT INTO lease (name, holder, expires, created, modified) VALUES (?1, ?2, ?3, ?5, ?5)
+             ON CONFLICT(name) DO UPDATE SET
+                 holder = excluded.holder,
+                 expires = excluded.expires,
+                 modified = excluded.modified
+             WHERE lease.expires <= ?4",
+            rusqlite::params![WRITER_LEASE, lease.holder.to_string(), lease.expires, now, self.timestamp()?])?;
+        if updates == 0 {
+            return Err(AppError::new_custom(
+                AppCustomErrorKind::RepositoryLease,
+                "writer lease is held by another process"));
+        }
+        Ok(lease)
+    }
+
+    /// Extend a lease for another `ttl`. Fails if the lease was taken over by another
+    /// holder after it expired.
+    pub fn renew_lease(&self, lease: &Lease, ttl: Duration) -> AppResult<Lease> {
+        let renewed = Lease { holder: lease.holder, expires: expiry(unix_seconds(self.clock())?, ttl)? };
+        let updates = self.database().execute(
+            "UPDATE lease SET expires = ?1, modified = ?4
+             WHERE name = ?2 AND holder = ?3",
+            rusqlite::params![renewed.expires, WRITER_LEASE, lease.holder.to_string(), self.timestamp()?])?;
+        if updates == 0 {
+            return Err(AppError::new_custom(
+                AppCustomErrorKind::RepositoryLease,
+                "writer lease was lost"));
+        }
+        Ok(renewed)
+    }
+
+    /// Give up a lease so other processes can write immediately
+    pub fn release_lease(&self, lease: Lease) -> AppResult<()> {
+        self.database().execute(
+            "DELETE FROM lease WHERE name = ?1 AND holder = ?2",
+            rusqlite::params![WRITER_LEASE, lease.holder.to_string()])?;
+        Ok(())
+    }
+}
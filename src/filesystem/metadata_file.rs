@@ -0,0 +1,231 @@
+//! Bulk import of user metadata from mapping files, as exported by spreadsheets and
+//! asset management systems. Each row names an entry by its BLAKE3 hash (`hash`
+//! column, hex) or its storage path (`path` column); every other column is applied as
+//! an annotation of the matching entries. Empty values are skipped. Column names must
+//! be unique and not empty.
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Read;
+use rusqlite::{params, Connection};
+use serde::de::{Deserialize, Deserializer, MapAccess, Visitor};
+use crate::filesystem::annotation::{annotation_changes, check_reserved, write_annotation};
+use crate::filesystem::error::{AppCustomErrorKind, AppError, AppResult};
+use crate::filesystem::freeze;
+use crate::filesystem::hash_format::from_hex;
+use crate::filesystem::repository::Repository;
+
+const HASH_COLUMN: &str = "hash";
+const PATH_COLUMN: &str = "path";
+
+/// Layout of a mapping file
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
+pub enum MappingFormat {
+    /// Comma separated values with a header row, quoted as in RFC 4180
+    Csv,
+    /// An array of objects with string values
+    Json,
+}
+
+/// Outcome of applying a mapping file
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetadataImport {
+    rows: usize,
+    annotated: usize,
+    unmatched: Vec<usize>,
+}
+
+impl MetadataImport {
+    /// Number of data rows in the file
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    /// Number of annotations set, or that would be set by a dry run
+    pub fn annotated(&self) -> usize {
+        self.annotated
+    }
+
+    /// Numbers, from 1, of the data rows matching no entry
+    pub fn unmatched(&self) -> &[usize] {
+        &self.unmatched
+    }
+}
+
+fn file_error(msg: &str) -> AppError {
+    AppError::new_custom(AppCustomErrorKind::MetadataFile, msg)
+}
+
+/// Split CSV content into records of fields
+fn parse_csv(content: &str) -> AppResult<Vec<Vec<String>>> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = content.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, quoted) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            ('"', true) => quoted = false,
+            ('"', false) if field.is_empty() => quoted = true,
+            (',', false) => record.push(std::mem::take(&mut field)),
+            ('\r', false) if chars.peek() == Some(&'\n') => {}
+            ('\n', false) => {
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
+            }
+            (c, _) => field.push(c),
+        }
+    }
+    if quoted {
+        return Err(file_error("unterminated quoted field"));
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// A JSON object as its members in file order, so that duplicate keys are not lost
+struct JsonRow(Vec<(String, String)>);
+
+impl<'de> Deserialize<'de> for JsonRow {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<JsonRow, D::Error> {
+        struct RowVisitor;
+
+        impl<'de> Visitor<'de> for RowVisitor {
+            type Value = JsonRow;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("an object with string values")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<JsonRow, A::Error> {
+                let mut members = Vec::new();
+                while let Some(member) = map.next_entry()? {
+                    members.push(member);
+                }
+                Ok(JsonRow(members))
+            }
+        }
+
+        deserializer.deserialize_map(RowVisitor)
+    }
+}
+
+/// Fail on an empty or repeated column name
+fn check_columns<'a>(columns: impl Iterator<Item = &'a str>, place: &str) -> AppResult<()> {
+    let mut seen = Vec::new();
+    for column in columns {
+        if column.is_empty() {
+            return Err(file_error(&format!("{}: empty column name", place)));
+        }
+        if seen.contains(&column) {
+            return Err(file_error(&format!("{}: duplicate column {}", place, column)));
+        }
+        seen.push(column);
+    }
+    Ok(())
+}
+
+/// Rows of a mapping file as column to value maps, in file order
+fn parse_rows(content: &str, format: MappingFormat) -> AppResult<Vec<BTreeMap<String, String>>> {
+    match format {
+        MappingFormat::Csv => {
+            let mut records = parse_csv(content)?.into_iter().filter(|r| r != &[""]);
+            let header = records.next().ok_or_else(|| file_error("missing header row"))?;
+            check_columns(header.iter().map(String::as_str), "header")?;
+            records.enumerate()
+                .map(|(index, record)| {
+                    if record.len() != header.len() {
+                        return Err(file_error(&format!("row {} has {} fields, the header {}",
+                                                       index + 1, record.len(), header.len())));
+                    }
+                    Ok(header.iter().cloned().zip(record).collect())
+                })
+                .collect()
+        }
+        MappingFormat::Json => {
+            let rows: Vec<JsonRow> = serde_json::from_str(content)
+                .map_err(|e| AppError::from_error(e, "parsing metadata file"))?;
+            rows.into_iter().enumerate()
+                .map(|(index, JsonRow(members))| {
+                    check_columns(members.iter().map(|(key, _)| key.as_str()), &format!("row {}", index + 1))?;
+                    Ok(members.into_iter().collect())
+                })
+                .collect()
+        }
+    }
+}
+
+/// Entries named by a row
+fn matching_entries(conn: &Connection, number: usize, row: &BTreeMap<String, String>) -> AppResult<Vec<String>> {
+    let (sql, key) = match (row.get(HASH_COLUMN).filter(|v| !v.is_empty()), row.get(PATH_COLUMN).filter(|v| !v.is_empty())) {
+        (Some(hash), _) => {
+            let hash = from_hex(&hash.to_lowercase())
+                .ok_or_else(|| file_error(&format!("row {}: invalid hash {}", number, hash)))?;
+            ("SELECT id FROM main_catalog WHERE hash = ?1 ORDER BY id", rusqlite::types::Value::Blob(hash))
+        }
+        (None, Some(path)) => ("SELECT id FROM main_catalog WHERE storage_path = ?1 ORDER BY id",
+                               rusqlite::types::Value::Text(path.clone())),
+        (None, None) => return Err(file_error(&format!("row {}: neither hash nor path given", number))),
+    };
+    let mut stmt = conn.prepare(sql).map_err(|e| AppError::from_error(e, "preparing entry match"))?;
+    stmt.query_map(params![key], |row| row.get(0))
+        .and_then(|rows| rows.collect())
+        .map_err(|e| AppError::from_error(e, &format!("matching row {}", number)))
+}
+
+impl Repository {
+    /// Apply the metadata of a mapping file to the entries it names. The whole file is
+    /// validated first, including the values of the reserved `rating` and `review_state`
+    /// columns, and nothing is applied if a row is malformed; rows matching no entry are
+    /// reported instead. A dry run checks every annotation as the real run would, so
+    /// that it fails alike on a frozen repository or an immutable entry, and only skips
+    /// the writes.
+    pub fn apply_metadata_file<R: Read>(&self, mut reader: R, format: MappingFormat, dry_run: bool) -> AppResult<MetadataImport> {
+        let mut content = String::new();
+        reader.read_to_string(&mut content)
+            .map_err(|e| AppError::from_error(e, "reading metadata file"))?;
+        // spreadsheet exports often start with a byte order mark
+        let rows = parse_rows(content.strip_prefix('\u{feff}').unwrap_or(&content), format)?;
+        let invalid: Vec<String> = rows.iter().enumerate()
+            .filter(|(_, row)| row.iter().any(|(key, value)| !value.is_empty() && check_reserved(key, value).is_err()))
+            .map(|(index, _)| (index + 1).to_string())
+            .collect();
+        if !invalid.is_empty() {
+            return Err(file_error(&format!("invalid rating or review state in rows {}", invalid.join(", "))));
+        }
+        freeze::ensure_writable(self.database().connection())?;
+
+        let now = self.timestamp()?;
+        let tx = self.database().connection().unchecked_transaction()
+            .map_err(|e| AppError::from_error(e, "starting metadata import"))?;
+        let mut report = MetadataImport { rows: rows.len(), annotated: 0, unmatched: vec![] };
+        for (index, row) in rows.iter().enumerate() {
+            let entries = matching_entries(&tx, index + 1, row)?;
+            if entries.is_empty() {
+                report.unmatched.push(index + 1);
+                continue;
+            }
+            let annotations = row.iter()
+                .filter(|(key, value)| *key != HASH_COLUMN && *key != PATH_COLUMN && !value.is_empty());
+            for (key, value) in annotations {
+                for id in entries.iter() {
+                    if dry_run {
+                        annotation_changes(&tx, id, key, Some(value))?;
+                    } else {
+                        write_annotation(&tx, &now, id, key, Some(value))?;
+                    }
+                    report.annotated += 1;
+                }
+            }
+        }
+        tx.commit().map_err(|e| AppError::from_error(e, "committing metadata import"))?;
+        Ok(report)
+    }
+}
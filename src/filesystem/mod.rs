@@ -1,2 +1,26 @@
+pub mod annotation;
+pub mod audit;
+pub mod changes;
+pub mod checksum;
+pub mod clock;
+pub mod content;
+pub mod digest;
+pub mod doctor;
 pub mod error;
-pub mod repository;
\ No newline at end of file
+pub mod extension;
+pub mod freeze;
+pub mod hash_format;
+pub mod immutability;
+pub mod lease;
+pub mod metadata_file;
+pub mod passport;
+pub mod queue;
+pub mod recovery_kit;
+pub mod relation;
+pub mod repository;
+pub mod shred;
+pub mod snapshot;
+pub mod storage;
+pub mod telemetry;
+pub mod unit_export;
+pub mod verify;
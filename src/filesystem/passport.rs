@@ -0,0 +1,152 @@
+//! Repository passports, for institutions tracking many offline archive drives. A
+//! passport is a short JSON summary of a repository (identity, entry count, size and a
+//! Merkle root over the whole catalog) that can be printed or mailed, and later checked
+//! against the drive it was issued for.
+//!
+//! The passport is sealed with a BLAKE3 key derived from the repository sign. This
+//! detects a passport edited by hand or presented for another repository. It is not a
+//! public-key signature: anybody holding the drive could issue a passport for it.
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::filesystem::digest::BLAKE3;
+use crate::filesystem::error::{AppCustomErrorKind, AppError, AppResult};
+use crate::filesystem::hash_format::decode_any;
+use crate::filesystem::repository::Repository;
+use crate::filesystem::storage::merkle_root;
+
+const PASSPORT_FORMAT: &str = "afilia-passport-1";
+const SEAL_CONTEXT: &str = "afilia 2024 repository passport seal";
+
+/// Summary of a repository at the time it was issued
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Passport {
+    format: String,
+    repository: Uuid,
+    name: String,
+    entries: u64,
+    size: u64,
+    storage_units: u64,
+    sealed_units: u64,
+    catalog_root: String,
+    issued: String,
+    seal: String,
+}
+
+impl Passport {
+    pub fn repository(&self) -> &Uuid {
+        &self.repository
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Number of catalog entries
+    pub fn entries(&self) -> u64 {
+        self.entries
+    }
+
+    /// Total size of the entries, in bytes
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn storage_units(&self) -> u64 {
+        self.storage_units
+    }
+
+    pub fn sealed_units(&self) -> u64 {
+        self.sealed_units
+    }
+
+    /// Hex Merkle root over the `(storage path, hash)` of every catalog entry
+    pub fn catalog_root(&self) -> &str {
+        &self.catalog_root
+    }
+
+    /// UTC timestamp of issue, as `YYYY-MM-DD HH:MM:SS`
+    pub fn issued(&self) -> &str {
+        &self.issued
+    }
+
+    pub fn to_json(&self) -> AppResult<String> {
+        serde_json::to_string_pretty(self)
+            .map_err(|e| AppError::from_error(e, "serializing passport"))
+    }
+
+    pub fn from_json(json: &str) -> AppResult<Passport> {
+        serde_json::from_str(json)
+            .map_err(|e| AppError::from_error(e, "parsing passport"))
+    }
+
+    fn sealed_content(&self) -> String {
+        format!("{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}", self.format, self.repository, self.name, self.entries,
+                self.size, self.storage_units, self.sealed_units, self.catalog_root, self.issued)
+    }
+}
+
+fn seal(repository: &Repository, passport: &Passport) -> String {
+    let key = blake3::derive_key(SEAL_CONTEXT, repository.sign().as_bytes());
+    blake3::keyed_hash(&key, passport.sealed_content().as_bytes()).to_hex().to_string()
+}
+
+impl Repository {
+    /// Issue a passport describing the current state of the repository
+    pub fn passport(&self) -> AppResult<Passport> {
+        let conn = self.database().connection();
+        let (entries, size): (i64, i64) = conn
+            .query_row("SELECT count(*), coalesce(sum(size), 0) FROM main_catalog", [], |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(|e| AppError::from_error(e, "counting catalog entries"))?;
+        let (storage_units, sealed_units): (i64, i64) = conn
+            .query_row("SELECT count(*), count(sealed) FROM storage_unit", [], |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(|e| AppError::from_error(e, "counting storage units"))?;
+        let mut stmt = conn.prepare("SELECT storage_path, hash FROM main_catalog ORDER BY storage_path")
+            .map_err(|e| AppError::from_error(e, "preparing catalog root query"))?;
+        let leaves: Vec<(String, Vec<u8>)> = stmt
+            .query_map(params![], |row| Ok((row.get(0)?, row.get(1)?)))
+            .and_then(|rows| rows.collect())
+            .map_err(|e| AppError::from_error(e, "reading catalog"))?;
+        let root = merkle_root(leaves.iter().map(|(path, hash)| (path.as_str(), hash.as_slice())));
+
+        let mut passport = Passport {
+            format: PASSPORT_FORMAT.to_string(),
+            repository: *self.uuid(),
+            name: self.name().to_string(),
+            entries: entries as u64,
+            size: size as u64,
+            storage_units: storage_units as u64,
+            sealed_units: sealed_units as u64,
+            catalog_root: self.render_hash(BLAKE3, root.as_bytes()),
+            issued: self.timestamp()?,
+            seal: String::new(),
+        };
+        passport.seal = seal(self, &passport);
+        Ok(passport)
+    }
+
+    /// Check this repository against a passport. Fails if the passport was not issued
+    /// by this repository or was altered; otherwise returns the names of the fields
+    /// which no longer match, empty when the repository is as described.
+    pub fn check_passport(&self, passport: &Passport) -> AppResult<Vec<&'static str>> {
+        if passport.format != PASSPORT_FORMAT {
+            return Err(AppError::new_custom(
+                AppCustomErrorKind::RepositorySign, &format!("unknown passport format {}", passport.format)));
+        }
+        if passport.repository != *self.uuid() || seal(self, passport) != passport.seal {
+            return Err(AppError::new_custom(
+                AppCustomErrorKind::RepositorySign,
+                &format!("passport of {} was not issued by repository {}", passport.repository, self.uuid())));
+        }
+        let current = self.passport()?;
+        let fields = [
+            ("name", current.name != passport.name),
+            ("entries", current.entries != passport.entries),
+            ("size", current.size != passport.size),
+            ("storage_units", current.storage_units != passport.storage_units),
+            ("sealed_units", current.sealed_units != passport.sealed_units),
+            ("catalog_root", decode_any(BLAKE3, &current.catalog_root) != decode_any(BLAKE3, &passport.catalog_root)),
+        ];
+        Ok(fields.iter().filter(|(_, differs)| *differs).map(|(field, _)| *field).collect())
+    }
+}
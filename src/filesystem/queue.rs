@@ -0,0 +1,159 @@
+//! Introspection of the ingestion queue. Items that failed stay in the queue with their
+//! last error and attempt count; after too many attempts they are moved to the dead
+//! letter state and left for an operator to requeue or discard.
+use rusqlite::types::Type;
+use rusqlite::ToSql;
+use crate::filesystem::audit;
+use crate::filesystem::error::{AppCustomErrorKind, AppError, AppResult};
+use crate::filesystem::freeze;
+use crate::filesystem::repository::Repository;
+
+/// State of a queue item
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum QueueStatus {
+    /// Waiting to be processed
+    Pending,
+    /// Failed at least once, will be retried
+    Failed,
+    /// Failed too often, no longer retried
+    DeadLetter,
+}
+
+impl QueueStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            QueueStatus::Pending => "pending",
+            QueueStatus::Failed => "failed",
+            QueueStatus::DeadLetter => "dead_letter",
+        }
+    }
+
+    fn parse(status: &str) -> Option<QueueStatus> {
+        match status {
+            "pending" => Some(QueueStatus::Pending),
+            "failed" => Some(QueueStatus::Failed),
+            "dead_letter" => Some(QueueStatus::DeadLetter),
+            _ => None,
+        }
+    }
+}
+
+/// An item of the ingestion queue
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueueItem {
+    id: String,
+    hash: Vec<u8>,
+    status: QueueStatus,
+    attempts: u32,
+    error: Option<String>,
+    modified: String,
+}
+
+impl QueueItem {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn hash(&self) -> &[u8] {
+        &self.hash
+    }
+
+    pub fn status(&self) -> QueueStatus {
+        self.status
+    }
+
+    /// Number of processing attempts so far
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Message of the last failure
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+
+    /// UTC timestamp of the last state change, as `YYYY-MM-DD HH:MM:SS`
+    pub fn modified(&self) -> &str {
+        &self.modified
+    }
+}
+
+impl Repository {
+    /// Queue items in `status`, or all of them, oldest first
+    pub fn queue_items(&self, status: Option<QueueStatus>) -> AppResult<Vec<QueueItem>> {
+        let mut stmt = self.database().connection()
+            .prepare("SELECT id, hash, status, attempts, error, modified FROM queue
+                      WHERE ?1 IS NULL OR status = ?1 ORDER BY created, id")
+            .map_err(|e| AppError::from_error(e, "preparing queue query"))?;
+        stmt.query_map([status.map(|s| s.as_str())], |row| {
+            let status = QueueStatus::parse(row.get_ref(2)?.as_str()?).ok_or_else(|| {
+                rusqlite::Error::FromSqlConversionFailure(2, Type::Text, "unknown queue status".into())
+            })?;
+            Ok(QueueItem {
+                id: row.get(0)?,
+                hash: row.get(1)?,
+                status,
+                attempts: row.get(3)?,
+                error: row.get(4)?,
+                modified: row.get(5)?,
+            })
+        })
+        .and_then(|rows| rows.collect())
+        .map_err(|e| AppError::from_error(e, "reading queue"))
+    }
+
+    /// Put an item back to pending, clearing its error but keeping its attempt count
+    pub fn requeue(&self, id: &str) -> AppResult<()> {
+        let now = self.timestamp()?;
+        let updates = self.change_queue(
+            &now, "requeue", &format!("queue item {}", id),
+            "UPDATE queue SET status = 'pending', error = NULL, modified = ?2 WHERE id = ?1", &[&id, &now])?;
+        ensure_found(updates, id)
+    }
+
+    /// Remove an item from the queue
+    pub fn discard(&self, id: &str) -> AppResult<()> {
+        let updates = self.change_queue(
+            &self.timestamp()?, "discard", &format!("queue item {}", id), "DELETE FROM queue WHERE id = ?1", &[&id])?;
+        ensure_found(updates, id)
+    }
+
+    /// Requeue every item in `status`, returning how many were
+    pub fn requeue_all(&self, status: QueueStatus) -> AppResult<usize> {
+        let now = self.timestamp()?;
+        self.change_queue(
+            &now, "requeue", &format!("{} queue items", status.as_str()),
+            "UPDATE queue SET status = 'pending', error = NULL, modified = ?2 WHERE status = ?1",
+            &[&status.as_str(), &now])
+    }
+
+    /// Remove every item in `status`, returning how many were
+    pub fn discard_all(&self, status: QueueStatus) -> AppResult<usize> {
+        self.change_queue(
+            &self.timestamp()?, "discard", &format!("{} queue items", status.as_str()),
+            "DELETE FROM queue WHERE status = ?1", &[&status.as_str()])
+    }
+
+    /// Run an operator change of the queue and audit it
+    fn change_queue(&self, now: &str, action: &str, detail: &str, sql: &str, params: &[&dyn ToSql]) -> AppResult<usize> {
+        freeze::ensure_writable(self.database().connection())?;
+        let tx = self.database().connection().unchecked_transaction()
+            .map_err(|e| AppError::from_error(e, &format!("starting queue {}", action)))?;
+        let updates = tx.execute(sql, params)
+            .map_err(|e| AppError::from_error(e, &format!("{} of {}", action, detail)))?;
+        if updates > 0 {
+            audit::record(&tx, now, action, None, Some(&format!("{} of {}", updates, detail)))?;
+        }
+        tx.commit().map_err(|e| AppError::from_error(e, &format!("committing queue {}", action)))?;
+        Ok(updates)
+    }
+}
+
+fn ensure_found(updates: usize, id: &str) -> AppResult<()> {
+    if updates == 0 {
+        return Err(AppError::new_custom(
+            AppCustomErrorKind::RepositoryMetadata, &format!("unknown queue item {}", id)));
+    }
+    Ok(())
+}
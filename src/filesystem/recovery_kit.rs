@@ -0,0 +1,99 @@
+//! Recovery kits: a small bundle holding what is needed to bootstrap the recovery of
+//! a damaged catalog, to be kept away from the repository. Exporting again to the same
+//! directory refreshes the kit.
+//!
+//! ```text
+//! <dest>/.afilia_repo      copy of the sign file
+//! <dest>/afilia_repo.db    consistent copy of the catalog database
+//! <dest>/RECOVERY.json     schema versions, parameters, passport and storage unit map
+//! ```
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use serde::Serialize;
+use uuid::Uuid;
+use crate::filesystem::digest::BLAKE3;
+use crate::filesystem::error::{AppError, AppResult};
+use crate::filesystem::passport::Passport;
+use crate::filesystem::repository::{Repository, DB_FILE_NAME, SIGN_FILE_NAME};
+
+pub const RECOVERY_INDEX_FILE_NAME: &str = "RECOVERY.json";
+
+#[derive(Serialize)]
+struct RecoveryIndex {
+    repository: Uuid,
+    created: String,
+    sqlite_version: String,
+    extension_schemas: BTreeMap<String, i64>,
+    parameters: BTreeMap<String, Option<String>>,
+    passport: Passport,
+    storage_units: Vec<RecoveryUnit>,
+}
+
+#[derive(Serialize)]
+struct RecoveryUnit {
+    id: i64,
+    path: String,
+    file_count: i64,
+    sealed: Option<String>,
+    manifest_root: Option<String>,
+}
+
+impl Repository {
+    /// Write a recovery kit of the repository to `dest`. The catalog copy is taken
+    /// atomically and replaces the previous one only once complete.
+    pub fn export_recovery_kit(&self, dest: &Path) -> AppResult<()> {
+        fs::create_dir_all(dest)
+            .map_err(|e| AppError::from_error(e, &format!("creating {}", dest.display())))?;
+        let sign_path = dest.join(SIGN_FILE_NAME);
+        fs::copy(self.path().join(SIGN_FILE_NAME), &sign_path)
+            .map_err(|e| AppError::from_error(e, &format!("copying sign file to {}", sign_path.display())))?;
+
+        let db_path = dest.join(DB_FILE_NAME);
+        let partial_path = dest.join(format!("{}.partial", DB_FILE_NAME));
+        if partial_path.exists() {
+            fs::remove_file(&partial_path)
+                .map_err(|e| AppError::from_error(e, &format!("removing {}", partial_path.display())))?;
+        }
+        let conn = self.database().connection();
+        conn.execute("VACUUM INTO ?1", [partial_path.to_string_lossy()])
+            .map_err(|e| AppError::from_error(e, &format!("copying catalog to {}", partial_path.display())))?;
+        fs::rename(&partial_path, &db_path)
+            .map_err(|e| AppError::from_error(e, &format!("renaming {}", partial_path.display())))?;
+
+        let mut stmt = conn.prepare("SELECT name, version FROM extension_schema ORDER BY name")
+            .map_err(|e| AppError::from_error(e, "preparing extension schemas query"))?;
+        let extension_schemas = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .and_then(|rows| rows.collect())
+            .map_err(|e| AppError::from_error(e, "reading extension schemas"))?;
+        let mut stmt = conn.prepare("SELECT key, value FROM parameter ORDER BY key")
+            .map_err(|e| AppError::from_error(e, "preparing parameters query"))?;
+        let parameters = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .and_then(|rows| rows.collect())
+            .map_err(|e| AppError::from_error(e, "reading parameters"))?;
+        let storage_units = self.storage_units()?.into_iter()
+            .map(|unit| RecoveryUnit {
+                id: unit.id(),
+                path: unit.path().to_string(),
+                file_count: unit.file_count(),
+                sealed: unit.sealed().map(String::from),
+                manifest_root: unit.manifest_root().map(|root| self.render_hash(BLAKE3, root)),
+            })
+            .collect();
+
+        let index = RecoveryIndex {
+            repository: *self.uuid(),
+            created: self.timestamp()?,
+            sqlite_version: rusqlite::version().to_string(),
+            extension_schemas,
+            parameters,
+            passport: self.passport()?,
+            storage_units,
+        };
+        let index_path = dest.join(RECOVERY_INDEX_FILE_NAME);
+        let content = serde_json::to_string_pretty(&index)
+            .map_err(|e| AppError::from_error(e, "serializing recovery index"))?;
+        fs::write(&index_path, content)
+            .map_err(|e| AppError::from_error(e, &format!("writing {}", index_path.display())))
+    }
+}
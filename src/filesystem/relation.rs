@@ -0,0 +1,157 @@
+//! Typed relations between entries, so that a mail and its attachments, a RAW file and
+//! its JPEG, or the revisions of a document are linked explicitly rather than by naming
+//! conventions. A relation goes from a source entry to a target entry, e.g. a JPEG is
+//! `DerivedFrom` its RAW file, and can be followed transitively in either direction.
+use std::collections::{HashMap, HashSet};
+use rusqlite::{params, Connection};
+use crate::filesystem::changes::{self, ChangeKind};
+use crate::filesystem::error::{AppCustomErrorKind, AppError, AppResult};
+use crate::filesystem::freeze;
+use crate::filesystem::immutability::ensure_entry_exists;
+use crate::filesystem::repository::Repository;
+
+/// Kind of a relation, read as "source <kind> target"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RelationKind {
+    DerivedFrom,
+    Supersedes,
+    PartOf,
+    AttachmentOf,
+}
+
+impl RelationKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            RelationKind::DerivedFrom => "derived_from",
+            RelationKind::Supersedes => "supersedes",
+            RelationKind::PartOf => "part_of",
+            RelationKind::AttachmentOf => "attachment_of",
+        }
+    }
+}
+
+/// Which way relations are followed from an entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Direction {
+    /// From source to target, e.g. to the revisions a document supersedes
+    Outgoing,
+    /// From target to source, e.g. to the attachments of a mail
+    Incoming,
+}
+
+fn ensure_distinct(source: &str, target: &str) -> AppResult<()> {
+    if source == target {
+        return Err(AppError::new_custom(
+            AppCustomErrorKind::RepositoryMetadata, &format!("entry {} cannot be related to itself", source)));
+    }
+    Ok(())
+}
+
+fn relation_error(e: rusqlite::Error, source: &str, kind: RelationKind, target: &str) -> AppError {
+    AppError::from_error(e, &format!("changing relation {} {} {}", source, kind.as_str(), target))
+}
+
+fn record_change(conn: &Connection, now: &str, source: &str, updates: usize) -> AppResult<()> {
+    if updates > 0 {
+        changes::record(conn, now, ChangeKind::RelationChanged, source)?;
+    }
+    Ok(())
+}
+
+impl Repository {
+    /// Record that `source` is in relation `kind` with `target`. Both entries must
+    /// exist; recording an existing relation again changes nothing.
+    pub fn relate(&self, source: &str, kind: RelationKind, target: &str) -> AppResult<()> {
+        freeze::ensure_writable(self.database().connection())?;
+        ensure_distinct(source, target)?;
+        ensure_entry_exists(self.database().connection(), source)?;
+        ensure_entry_exists(self.database().connection(), target)?;
+        let now = self.timestamp()?;
+        let tx = self.database().connection().unchecked_transaction()
+            .map_err(|e| AppError::from_error(e, "starting relation change"))?;
+        let updates = tx.execute(
+            "INSERT OR IGNORE INTO relation (source_id, kind, target_id, created) VALUES (?1, ?2, ?3, ?4)",
+            params![source, kind.as_str(), target, now])
+            .map_err(|e| relation_error(e, source, kind, target))?;
+        record_change(&tx, &now, source, updates)?;
+        tx.commit().map_err(|e| AppError::from_error(e, "committing relation change"))
+    }
+
+    /// Remove a relation, if it exists
+    pub fn unrelate(&self, source: &str, kind: RelationKind, target: &str) -> AppResult<()> {
+        freeze::ensure_writable(self.database().connection())?;
+        let now = self.timestamp()?;
+        let tx = self.database().connection().unchecked_transaction()
+            .map_err(|e| AppError::from_error(e, "starting relation change"))?;
+        let updates = tx.execute(
+            "DELETE FROM relation WHERE source_id = ?1 AND kind = ?2 AND target_id = ?3",
+            params![source, kind.as_str(), target])
+            .map_err(|e| relation_error(e, source, kind, target))?;
+        record_change(&tx, &now, source, updates)?;
+        tx.commit().map_err(|e| AppError::from_error(e, "committing relation change"))
+    }
+
+    /// Entries directly in relation `kind` with `id`, in `direction`, sorted by id
+    pub fn related(&self, id: &str, kind: RelationKind, direction: Direction) -> AppResult<Vec<String>> {
+        let sql = match direction {
+            Direction::Outgoing => "SELECT target_id FROM relation WHERE source_id = ?1 AND kind = ?2 ORDER BY 1",
+            Direction::Incoming => "SELECT source_id FROM relation WHERE target_id = ?1 AND kind = ?2 ORDER BY 1",
+        };
+        self.relation_query(sql, id, kind)
+    }
+
+    /// Entries reachable from `id` by following relations `kind` in `direction` any
+    /// number of times, e.g. the whole revision chain of a document, closest first.
+    /// The reachable relations are read with one recursive query, whose `UNION` visits
+    /// every entry once so that cycles end, then ordered by distance and id.
+    pub fn traverse(&self, id: &str, kind: RelationKind, direction: Direction) -> AppResult<Vec<String>> {
+        let (from, to) = match direction {
+            Direction::Outgoing => ("source_id", "target_id"),
+            Direction::Incoming => ("target_id", "source_id"),
+        };
+        let sql = format!(
+            "WITH RECURSIVE reach(id) AS (
+                 SELECT ?1
+                 UNION
+                 SELECT r.{to} FROM relation r JOIN reach ON r.{from} = reach.id WHERE r.kind = ?2)
+             SELECT r.{from}, r.{to} FROM relation r JOIN reach ON r.{from} = reach.id
+             WHERE r.kind = ?2 ORDER BY 1, 2",
+            from = from, to = to);
+        let mut stmt = self.database().connection()
+            .prepare(&sql)
+            .map_err(|e| AppError::from_error(e, "preparing relation traversal"))?;
+        let edges: Vec<(String, String)> = stmt
+            .query_map(params![id, kind.as_str()], |row| Ok((row.get(0)?, row.get(1)?)))
+            .and_then(|rows| rows.collect())
+            .map_err(|e| AppError::from_error(e, &format!("traversing {} relations of {}", kind.as_str(), id)))?;
+        let mut neighbours: HashMap<&str, Vec<&str>> = HashMap::new();
+        for (source, target) in &edges {
+            neighbours.entry(source.as_str()).or_default().push(target.as_str());
+        }
+
+        let mut visited = HashSet::from([id]);
+        let mut reachable = vec![];
+        let mut frontier = vec![id];
+        while !frontier.is_empty() {
+            let mut next: Vec<&str> = frontier.iter()
+                .flat_map(|current| neighbours.get(current).into_iter().flatten().copied())
+                .filter(|related| visited.insert(*related))
+                .collect();
+            next.sort_unstable();
+            reachable.extend(next.iter().map(|related| related.to_string()));
+            frontier = next;
+        }
+        Ok(reachable)
+    }
+
+    fn relation_query(&self, sql: &str, id: &str, kind: RelationKind) -> AppResult<Vec<String>> {
+        let mut stmt = self.database().connection()
+            .prepare(sql)
+            .map_err(|e| AppError::from_error(e, "preparing relation query"))?;
+        stmt.query_map(params![id, kind.as_str()], |row| row.get(0))
+            .and_then(|rows| rows.collect())
+            .map_err(|e| AppError::from_error(e, &format!("reading {} relations of {}", kind.as_str(), id)))
+    }
+}
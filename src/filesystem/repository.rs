@@ -1,19 +1,40 @@
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use uuid::Uuid;
 use serde::{Serialize, Deserialize};
-use blake3;
 use blake3::Hash;
-use rusqlite::{Connection, Error, Params};
-use crate::filesystem::error::{AppError, AppResult, InternalError};
+use rusqlite::{Connection, Params};
+use crate::filesystem::clock::{self, Clock, SystemClock};
+use crate::filesystem::hash_format::HashFormat;
+use crate::filesystem::error::{AppCustomErrorKind, AppError, AppResult};
+use crate::filesystem::storage;
+use crate::filesystem::telemetry::{NoopTelemetry, TelemetrySink};
+#[cfg(feature = "testkit")]
+use crate::filesystem::verify::SampleRng;
 
 
-const SIGN_FILE_NAME: &'static str = ".afilia_repo";
-const DB_FILE_NAME: &'static str = "afilia_repo.db";
-const REPO_FORMAT_VERSION : &'static str = "1.0";
+pub(crate) const SIGN_FILE_NAME: &str = ".afilia_repo";
+pub(crate) const DB_FILE_NAME: &str = "afilia_repo.db";
+const REPO_FORMAT_VERSION : &str = "1.0";
 
-#[derive(Serialize, Deserialize)]
+/// Triggers refusing catalog writes into a sealed unit: no entry may be added to it,
+/// and the storage path and hash of its entries, which its root covers, are fixed.
+/// Removing entries stays allowed, as shredding reseals the unit.
+pub(crate) const SEALED_UNIT_TRIGGERS: &[&str] = &[
+    "CREATE TRIGGER IF NOT EXISTS sealed_unit_insert BEFORE INSERT ON main_catalog
+     WHEN EXISTS (SELECT 1 FROM storage_unit WHERE sealed IS NOT NULL
+                  AND substr(NEW.storage_path, 1, length(path) + 1) = path || '/')
+     BEGIN SELECT RAISE(ABORT, 'storage unit is sealed'); END",
+    "CREATE TRIGGER IF NOT EXISTS sealed_unit_update BEFORE UPDATE OF storage_path, hash ON main_catalog
+     WHEN EXISTS (SELECT 1 FROM storage_unit WHERE sealed IS NOT NULL
+                  AND (substr(OLD.storage_path, 1, length(path) + 1) = path || '/'
+                       OR substr(NEW.storage_path, 1, length(path) + 1) = path || '/'))
+     BEGIN SELECT RAISE(ABORT, 'storage unit is sealed'); END",
+];
+
+#[derive(Clone, Serialize, Deserialize)]
 struct RepositoryID {
     uuid: Uuid,
     name: String,
@@ -22,106 +43,323 @@ struct RepositoryID {
 
 impl RepositoryID {
 
-    pub fn new(name: &str, payload: &str) -> RepositoryID {
-        let repo_uuid = Uuid::new_v4();
-        let repo_id = Self {
+    pub fn new(repo_uuid: Uuid, name: &str, payload: &str) -> RepositoryID {
+        Self {
             uuid: repo_uuid,
             name: String::from(name),
             sign: format!("{}", RepositoryID::sign(&repo_uuid, name, payload).to_hex())
-        };
-        return repo_id;
+        }
+    }
+
+    pub fn serialize(&self, path: &Path) -> AppResult<()> {
+        let sign_path = path.join(SIGN_FILE_NAME);
+        let mut file = File::create(sign_path.as_path())
+            .map_err(|e| AppError::from_error(e, &format!("creating {}", sign_path.display())))?;
+        let content = serde_json::to_string_pretty(self)
+            .map_err(|e| AppError::from_error(e, "serializing repository id"))?;
+        writeln!(&mut file, "{}", content)
+            .map_err(|e| AppError::from_error(e, &format!("writing {}", sign_path.display())))
     }
 
-    pub fn serialize(&self, path: &str) {
-        let path = PathBuf::from(path);
-        let mut file = File::create(path.join(SIGN_FILE_NAME).as_path()).unwrap();
-        let content = serde_json::to_string_pretty(self).unwrap();
-        writeln!(&mut file, "{}", content).unwrap();
+    pub fn deserialize(path: &Path) -> AppResult<RepositoryID> {
+        let sign_path = path.join(SIGN_FILE_NAME);
+        if !sign_path.is_file() {
+            return Err(AppError::new_custom(
+                AppCustomErrorKind::RepositoryStructure,
+                &format!("{} is not an afilia repository", path.display())));
+        }
+        let content = fs::read_to_string(sign_path.as_path())
+            .map_err(|e| AppError::from_error(e, &format!("reading {}", sign_path.display())))?;
+        serde_json::from_str(&content)
+            .map_err(|e| AppError::from_error(e, &format!("parsing {}", sign_path.display())))
     }
 
     fn sign(repo_uuid: &Uuid, name: &str, payload: &str) -> Hash {
-        return blake3::hash(format!("{}:{}:{}", repo_uuid, name, payload).as_bytes());
+        blake3::hash(format!("{}:{}:{}", repo_uuid, name, payload).as_bytes())
     }
 }
 
-struct RepositoryDB {
-    name: String,
-    conn: Option<Connection>
+/// Source of the UUIDs generated through a repository handle
+pub(crate) enum IdSource {
+    Random,
+    #[cfg(feature = "testkit")]
+    Seeded(std::sync::Mutex<SampleRng>),
+}
+
+impl IdSource {
+    fn next(&self) -> Uuid {
+        match self {
+            IdSource::Random => Uuid::new_v4(),
+            #[cfg(feature = "testkit")]
+            IdSource::Seeded(rng) => {
+                let mut rng = rng.lock().unwrap_or_else(|e| e.into_inner());
+                let mut bytes = [0u8; 16];
+                bytes[..8].copy_from_slice(&rng.next().to_le_bytes());
+                bytes[8..].copy_from_slice(&rng.next().to_le_bytes());
+                uuid::Builder::from_bytes(bytes)
+                    .set_variant(uuid::Variant::RFC4122)
+                    .set_version(uuid::Version::Random)
+                    .build()
+            }
+        }
+    }
+}
+
+pub(crate) struct RepositoryDB {
+    conn: Connection
 }
 
 impl RepositoryDB {
 
-    pub fn new(name: &str, path: &PathBuf) -> RepositoryDB {
-        Self {
-            name: String::from(name),
-            conn: Connection::open(path.join(DB_FILE_NAME).as_path()).ok()
-        }
+    pub fn new(path: &Path) -> AppResult<RepositoryDB> {
+        let db_path = path.join(DB_FILE_NAME);
+        let conn = Connection::open(db_path.as_path())
+            .map_err(|e| AppError::from_error(e, &format!("opening {}", db_path.display())))?;
+        // readers keep a consistent view while a writer commits
+        conn.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(()))
+            .map_err(|e| AppError::from_error(e, &format!("enabling WAL on {}", db_path.display())))?;
+        Ok(Self { conn })
     }
 
     pub fn execute<P: Params>(&self, sql: &str, params: P) -> AppResult<usize> {
-        match self.conn.as_ref().unwrap().execute(sql, params) {
+        match self.conn.execute(sql, params) {
             Ok(updates) => Ok(updates),
             Err(err) => Err(AppError::from_error(err, ""))
         }
     }
 
-    pub fn create(&self) -> AppResult<()> {
+    pub fn connection(&self) -> &Connection {
+        &self.conn
+    }
+
+    pub fn create(&self, now: &str) -> AppResult<()> {
         let sql_script = [
-            "CREATE TABLE storage_unit (id INTEGER PRIMARY KEY, path VARCHAR NOT NULL, file_count INTEGER DEFAULT 0)",
             "CREATE TABLE IF NOT EXISTS main_catalog (
                  id CHAR(36) PRIMARY KEY,
                  hash BLOB NOT NULL,
-                 storage_path VARCHAR(
+                 storage_path VARCHAR NOT NULL,
+                 size INTEGER DEFAULT 0 NOT NULL,
+                 immutable BOOLEAN DEFAULT 0 NOT NULL,
                  created TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
                  modified TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL)",
-            "CREATE TABLE storage_unit (
+            "CREATE TABLE IF NOT EXISTS entry_digest (
+                 entry_id CHAR(36) NOT NULL REFERENCES main_catalog(id),
+                 algorithm VARCHAR(16) NOT NULL,
+                 hash BLOB NOT NULL,
+                 created TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+                 PRIMARY KEY (entry_id, algorithm))",
+            "CREATE INDEX IF NOT EXISTS entry_digest_hash ON entry_digest (algorithm, hash)",
+            "CREATE TABLE IF NOT EXISTS storage_unit (
                  id INTEGER PRIMARY KEY,
                  path VARCHAR NOT NULL,
-                 file_count INTEGER DEFAULT 0)",
+                 file_count INTEGER DEFAULT 0,
+                 sealed TIMESTAMP,
+                 manifest_root BLOB)",
             "CREATE TABLE IF NOT EXISTS queue (
                  id CHAR(36) PRIMARY KEY,
                  hash BLOB NOT NULL,
+                 status VARCHAR(16) DEFAULT 'pending' NOT NULL,
+                 attempts INTEGER DEFAULT 0 NOT NULL,
+                 error VARCHAR,
                  created TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
                  modified TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL)",
             "CREATE TABLE IF NOT EXISTS parameter (
                  key VARCHAR(32) PRIMARY KEY,
                  value VARCHAR(256),
                  created TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+                 modified TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL)",
+            "CREATE TABLE IF NOT EXISTS provisional_entry (
+                 id CHAR(36) PRIMARY KEY,
+                 path VARCHAR NOT NULL,
+                 size INTEGER,
+                 created TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+                 modified TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL)",
+            "CREATE TABLE IF NOT EXISTS provisional_digest (
+                 entry_id CHAR(36) NOT NULL REFERENCES provisional_entry(id),
+                 algorithm VARCHAR(16) NOT NULL,
+                 hash BLOB NOT NULL,
+                 PRIMARY KEY (entry_id, algorithm))",
+            "CREATE TABLE IF NOT EXISTS entry_annotation (
+                 entry_id CHAR(36) NOT NULL,
+                 key VARCHAR(64) NOT NULL,
+                 value VARCHAR NOT NULL,
+                 created TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+                 modified TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+                 PRIMARY KEY (entry_id, key))",
+            "CREATE TABLE IF NOT EXISTS relation (
+                 source_id CHAR(36) NOT NULL,
+                 kind VARCHAR(16) NOT NULL,
+                 target_id CHAR(36) NOT NULL,
+                 created TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+                 PRIMARY KEY (source_id, kind, target_id))",
+            "CREATE INDEX IF NOT EXISTS relation_target ON relation (target_id, kind)",
+            "CREATE TABLE IF NOT EXISTS tombstone (
+                 id CHAR(36) PRIMARY KEY,
+                 created TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL)",
+            "CREATE TABLE IF NOT EXISTS audit_log (
+                 id INTEGER PRIMARY KEY,
+                 action VARCHAR(32) NOT NULL,
+                 entry_id CHAR(36),
+                 detail VARCHAR,
+                 created TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL)",
+            "CREATE TABLE IF NOT EXISTS oplog (
+                 seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                 operation VARCHAR(32) NOT NULL,
+                 entry_id CHAR(36) NOT NULL,
+                 created TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL)",
+            "CREATE TABLE IF NOT EXISTS extension_schema (
+                 name VARCHAR(32) PRIMARY KEY,
+                 version INTEGER NOT NULL,
+                 created TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+                 modified TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL)",
+            "CREATE TABLE IF NOT EXISTS lease (
+                 name VARCHAR(32) PRIMARY KEY,
+                 holder CHAR(36) NOT NULL,
+                 expires INTEGER NOT NULL,
+                 created TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
                  modified TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL)"
         ];
-        for sql in sql_script {
-            self.execute(sql, []);
-        }
-
-        return Ok(());
-    }
-
-    fn connect(path: &PathBuf) -> Option<Connection> {
-        match Connection::open(path.as_path()) {
-            Ok(conn) => Some(conn),
-            Err(_) => None
+        for sql in sql_script.iter().chain(SEALED_UNIT_TRIGGERS) {
+            self.execute(sql, [])?;
         }
+        self.execute(
+            "INSERT OR REPLACE INTO parameter (key, value, created, modified) VALUES ('format_version', ?1, ?2, ?2)",
+            [REPO_FORMAT_VERSION, now])?;
 
+        Ok(())
     }
 }
 
 pub struct Repository {
     id: RepositoryID,
     database: RepositoryDB,
-    path: PathBuf
+    path: PathBuf,
+    clock: Arc<dyn Clock>,
+    hash_format: HashFormat,
+    telemetry: Arc<dyn TelemetrySink>,
+    ids: IdSource
 }
 
 impl Repository {
 
-    pub fn create(path: &str, name: &str, payload: &str) -> Repository {
+    pub fn create(path: &str, name: &str, payload: &str) -> AppResult<Repository> {
+        Self::create_with(path, Uuid::new_v4(), name, payload, Arc::new(SystemClock), IdSource::Random)
+    }
+
+    /// Create a repository along with its first storage unit, in directory `root`
+    /// (usually `storage::DEFAULT_STORAGE_ROOT`). The directory is created first, so
+    /// that no repository is left behind if it cannot be.
+    pub fn create_with_storage_root(path: &str, name: &str, payload: &str, root: &str) -> AppResult<Repository> {
+        storage::create_unit_dir(Path::new(path), root)?;
+        let repository = Self::create(path, name, payload)?;
+        repository.create_storage_unit(root)?;
+        Ok(repository)
+    }
+
+    /// Create a repository with a given UUID, time source and id generator
+    pub(crate) fn create_with(path: &str, uuid: Uuid, name: &str, payload: &str,
+                              clock: Arc<dyn Clock>, ids: IdSource) -> AppResult<Repository> {
         let repopath = PathBuf::from(path);
         let repository = Self {
-            id: RepositoryID::new(name, payload),
-            database: RepositoryDB::new("repo1",&repopath),
-            path: repopath
+            id: RepositoryID::new(uuid, name, payload),
+            database: RepositoryDB::new(&repopath)?,
+            path: repopath,
+            clock,
+            hash_format: HashFormat::default(),
+            telemetry: Arc::new(NoopTelemetry),
+            ids
         };
-        repository.id.serialize(path);
-        repository.database.create();
-        return repository;
+        repository.id.serialize(&repository.path)?;
+        repository.database.create(&repository.timestamp()?)?;
+        Ok(repository)
+    }
+
+    pub fn open(path: &str) -> AppResult<Repository> {
+        let repopath = PathBuf::from(path);
+        Ok(Self {
+            id: RepositoryID::deserialize(&repopath)?,
+            database: RepositoryDB::new(&repopath)?,
+            path: repopath,
+            clock: Arc::new(SystemClock),
+            hash_format: HashFormat::default(),
+            telemetry: Arc::new(NoopTelemetry),
+            ids: IdSource::Random
+        })
+    }
+
+    /// Another handle on the same repository, with its own database connection
+    pub(crate) fn reopen(&self) -> AppResult<Repository> {
+        Ok(Self {
+            id: self.id.clone(),
+            database: RepositoryDB::new(&self.path)?,
+            path: self.path.clone(),
+            clock: self.clock.clone(),
+            hash_format: self.hash_format,
+            telemetry: self.telemetry.clone(),
+            ids: IdSource::Random
+        })
+    }
+
+    pub fn uuid(&self) -> &Uuid {
+        &self.id.uuid
+    }
+
+    pub fn name(&self) -> &str {
+        &self.id.name
+    }
+
+    /// Sign of the repository, as stored in its sign file
+    pub(crate) fn sign(&self) -> &str {
+        &self.id.sign
+    }
+
+    pub(crate) fn database(&self) -> &RepositoryDB {
+        &self.database
+    }
+
+    /// Replace the time source of this handle, e.g. with a `TestClock`
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
     }
-}
\ No newline at end of file
+
+    pub fn clock(&self) -> &dyn Clock {
+        self.clock.as_ref()
+    }
+
+    /// Current time of the repository clock, formatted for the database
+    pub(crate) fn timestamp(&self) -> AppResult<String> {
+        clock::timestamp(self.clock())
+    }
+
+    /// A new UUID, for leases or entries
+    pub(crate) fn new_id(&self) -> Uuid {
+        self.ids.next()
+    }
+
+    /// Choose how this handle renders hashes in the records it writes
+    pub fn set_hash_format(&mut self, hash_format: HashFormat) {
+        self.hash_format = hash_format;
+    }
+
+    pub fn hash_format(&self) -> HashFormat {
+        self.hash_format
+    }
+
+    /// Render a digest of `algorithm` in the format of this handle
+    pub fn render_hash(&self, algorithm: &str, hash: &[u8]) -> String {
+        self.hash_format.encode(algorithm, hash)
+    }
+
+    /// Route the signals of this handle to `telemetry`
+    pub fn set_telemetry(&mut self, telemetry: Arc<dyn TelemetrySink>) {
+        self.telemetry = telemetry;
+    }
+
+    pub fn telemetry(&self) -> &dyn TelemetrySink {
+        self.telemetry.as_ref()
+    }
+
+    pub fn path(&self) -> &Path {
+        self.path.as_path()
+    }
+}
@@ -0,0 +1,119 @@
+//! Secure removal of catalog entries, for erasure requests. The blob is overwritten
+//! before being unlinked; on copy-on-write filesystems or flash storage the previous
+//! blocks may survive, so this is a best effort at the filesystem level.
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use rusqlite::{params, OptionalExtension};
+use crate::filesystem::audit;
+use crate::filesystem::changes::{self, ChangeKind};
+use crate::filesystem::error::{AppCustomErrorKind, AppError, AppResult};
+use crate::filesystem::freeze;
+use crate::filesystem::immutability;
+use crate::filesystem::repository::Repository;
+
+const SHRED_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Overwrite a file with zeros, flush it to disk and remove it. A missing file is
+/// not an error, there is nothing left to shred.
+fn shred_file(path: &Path) -> io::Result<bool> {
+    let mut file = match OpenOptions::new().write(true).open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err),
+    };
+    let mut remaining = file.metadata()?.len();
+    let zeros = [0u8; SHRED_BUFFER_SIZE];
+    while remaining > 0 {
+        let chunk = remaining.min(SHRED_BUFFER_SIZE as u64) as usize;
+        file.write_all(&zeros[..chunk])?;
+        remaining -= chunk as u64;
+    }
+    file.sync_all()?;
+    fs::remove_file(path)?;
+    Ok(true)
+}
+
+impl Repository {
+    /// Erase an entry: drop its catalog row leaving only a tombstone with its id, record
+    /// the redaction in the audit log, then overwrite and remove its blob. A blob still
+    /// referenced by other entries is kept. Immutable entries must be unflagged first, and
+    /// so must the immutable entries it is related to, as their relations are removed
+    /// with it. If the entry belongs to a sealed storage unit, the unit root is updated
+    /// in the same transaction, audited as `reseal`, and its manifest rewritten after.
+    ///
+    /// The blob is only destroyed once the catalog change is committed. Should that
+    /// fail, the entry is gone but its blob is left in place, which is audited as
+    /// `shred_incomplete`.
+    pub fn shred(&self, id: &str) -> AppResult<()> {
+        let conn = self.database().connection();
+        freeze::ensure_writable(conn)?;
+        immutability::ensure_mutable(conn, id)?;
+        let storage_path: String = conn
+            .query_row("SELECT storage_path FROM main_catalog WHERE id = ?1", [id], |row| row.get(0))
+            .optional()
+            .map_err(|e| AppError::from_error(e, &format!("looking up entry {}", id)))?
+            .ok_or_else(|| AppError::new_custom(
+                AppCustomErrorKind::RepositoryMetadata, &format!("unknown entry {}", id)))?;
+        let blob_path = self.path().join(&storage_path);
+        let immutable_related: Option<String> = conn
+            .query_row(
+                "SELECT c.id FROM relation r
+                 JOIN main_catalog c ON c.id = CASE WHEN r.source_id = ?1 THEN r.target_id ELSE r.source_id END
+                 WHERE (r.source_id = ?1 OR r.target_id = ?1) AND c.immutable
+                 ORDER BY c.id LIMIT 1",
+                [id], |row| row.get(0))
+            .optional()
+            .map_err(|e| AppError::from_error(e, &format!("looking up relations of {}", id)))?;
+        if let Some(other) = immutable_related {
+            return Err(AppError::new_custom(
+                AppCustomErrorKind::ImmutableEntry,
+                &format!("entry {} is related to immutable entry {}", id, other)));
+        }
+
+        let sealed_unit = self.sealed_unit_of(&storage_path)?;
+        let now = self.timestamp()?;
+        let tx = conn.unchecked_transaction()
+            .map_err(|e| AppError::from_error(e, "starting shred"))?;
+        tx.execute("DELETE FROM entry_digest WHERE entry_id = ?1", [id])
+            .map_err(|e| AppError::from_error(e, &format!("removing digests of {}", id)))?;
+        tx.execute("DELETE FROM entry_annotation WHERE entry_id = ?1", [id])
+            .map_err(|e| AppError::from_error(e, &format!("removing annotations of {}", id)))?;
+        tx.execute("DELETE FROM relation WHERE source_id = ?1 OR target_id = ?1", [id])
+            .map_err(|e| AppError::from_error(e, &format!("removing relations of {}", id)))?;
+        tx.execute("DELETE FROM main_catalog WHERE id = ?1", [id])
+            .map_err(|e| AppError::from_error(e, &format!("removing entry {}", id)))?;
+        tx.execute("INSERT INTO tombstone (id, created) VALUES (?1, ?2)", params![id, now])
+            .map_err(|e| AppError::from_error(e, &format!("recording tombstone of {}", id)))?;
+        let sharing: i64 = tx
+            .query_row("SELECT count(*) FROM main_catalog WHERE storage_path = ?1", [&storage_path], |row| row.get(0))
+            .map_err(|e| AppError::from_error(e, &format!("counting references to {}", storage_path)))?;
+        let detail = if sharing > 0 {
+            format!("blob kept, shared with {} other entries", sharing)
+        } else if blob_path.exists() {
+            "blob overwritten and removed".to_string()
+        } else {
+            "blob already missing".to_string()
+        };
+        audit::record(&tx, &now, "shred", Some(id), Some(&detail))?;
+        changes::record(&tx, &now, ChangeKind::Shredded, id)?;
+        let unit_entries = sealed_unit.as_ref()
+            .map(|unit| self.reseal_storage_unit(&tx, &now, unit))
+            .transpose()?;
+        tx.commit().map_err(|e| AppError::from_error(e, "committing shred"))?;
+        if let (Some(unit), Some(entries)) = (sealed_unit, unit_entries) {
+            self.write_manifest(&unit, &entries)?;
+        }
+
+        if sharing == 0 {
+            if let Err(err) = shred_file(&blob_path) {
+                audit::record(conn, &now, "shred_incomplete", Some(id),
+                              Some(&format!("blob {} left in place: {}", storage_path, err)))?;
+                return Err(AppError::from_error(err, &format!("shredding {}", blob_path.display())));
+            }
+        }
+        self.telemetry().event("shred", &[("entry", id), ("detail", &detail)]);
+        self.telemetry().counter("afilia_entries_shredded_total", 1);
+        Ok(())
+    }
+}
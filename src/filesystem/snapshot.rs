@@ -0,0 +1,36 @@
+//! Pinned read transactions. The repository database runs in WAL mode, so each query
+//! sees the catalog as committed when it started, whatever writers do meanwhile. A
+//! snapshot extends that guarantee over several queries: it is a handle whose own
+//! connection keeps one read transaction open for as long as it lives.
+use std::ops::Deref;
+use crate::filesystem::error::{AppError, AppResult};
+use crate::filesystem::repository::Repository;
+
+/// A read-only view of the repository, fixed when it was taken. All the read methods
+/// of `Repository` are available through it; its connection is query-only, so every
+/// write, including lease operations, fails instead of taking the write lock.
+pub struct Snapshot {
+    repository: Repository,
+}
+
+impl Deref for Snapshot {
+    type Target = Repository;
+
+    fn deref(&self) -> &Repository {
+        &self.repository
+    }
+}
+
+impl Repository {
+    /// Pin the current state of the catalog for a series of consistent reads
+    pub fn snapshot(&self) -> AppResult<Snapshot> {
+        let repository = self.reopen()?;
+        let conn = repository.database().connection();
+        conn.execute_batch("PRAGMA query_only = ON; BEGIN DEFERRED")
+            .map_err(|e| AppError::from_error(e, "starting snapshot"))?;
+        // a WAL read transaction takes its snapshot at the first read
+        conn.query_row("SELECT count(*) FROM main_catalog", [], |_| Ok(()))
+            .map_err(|e| AppError::from_error(e, "pinning snapshot"))?;
+        Ok(Snapshot { repository })
+    }
+}
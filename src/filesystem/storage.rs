@@ -0,0 +1,381 @@
+//! Storage units are the directories holding blobs. An entry belongs to the unit whose
+//! path prefixes its storage path. A unit can be sealed once it is full: its content is
+//! fixed by a Merkle root recorded in the catalog, and a manifest is written next to the
+//! blobs, so that the unit can be moved to read-only media.
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use blake3::Hash;
+use rusqlite::{params, Connection, OptionalExtension};
+use crate::filesystem::audit;
+use crate::filesystem::digest::BLAKE3;
+use crate::filesystem::error::{AppCustomErrorKind, AppError, AppResult};
+use crate::filesystem::freeze;
+use crate::filesystem::hash_format::to_hex;
+use crate::filesystem::repository::Repository;
+use crate::filesystem::verify::hash_blob;
+
+/// Name of the manifest written in a sealed unit directory, as checked by `b3sum -c`
+pub const MANIFEST_FILE_NAME: &str = "MANIFEST.b3";
+
+/// Directory of the first storage unit, unless configured otherwise
+pub const DEFAULT_STORAGE_ROOT: &str = "objects";
+
+/// Outcome of checking a sealed unit against the root recorded for it
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnitCheck {
+    unit_id: i64,
+    mismatched: Vec<String>,
+}
+
+impl UnitCheck {
+    pub fn unit_id(&self) -> i64 {
+        self.unit_id
+    }
+
+    pub fn is_intact(&self) -> bool {
+        self.mismatched.is_empty()
+    }
+
+    /// Paths, relative to the unit, whose blob is missing or altered, or whose catalog
+    /// record differs from the unit manifest. The manifest itself is listed when it no
+    /// longer matches the recorded root.
+    pub fn mismatched(&self) -> &[String] {
+        &self.mismatched
+    }
+}
+
+/// A directory of blobs and its catalog record
+#[derive(Debug, Clone, PartialEq)]
+pub struct StorageUnit {
+    id: i64,
+    path: String,
+    file_count: i64,
+    sealed: Option<String>,
+    manifest_root: Option<Vec<u8>>,
+}
+
+impl StorageUnit {
+    pub fn id(&self) -> i64 {
+        self.id
+    }
+
+    /// Directory of the unit, relative to the repository root
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn file_count(&self) -> i64 {
+        self.file_count
+    }
+
+    /// UTC timestamp of the sealing, if the unit is sealed
+    pub fn sealed(&self) -> Option<&str> {
+        self.sealed.as_deref()
+    }
+
+    /// Merkle root of the unit content, recorded when it was sealed
+    pub fn manifest_root(&self) -> Option<&[u8]> {
+        self.manifest_root.as_deref()
+    }
+}
+
+const STORAGE_UNIT_COLUMNS: &str = "id, path, file_count, sealed, manifest_root";
+
+fn storage_unit_from_row(row: &rusqlite::Row) -> rusqlite::Result<StorageUnit> {
+    Ok(StorageUnit {
+        id: row.get(0)?,
+        path: row.get(1)?,
+        file_count: row.get(2)?,
+        sealed: row.get(3)?,
+        manifest_root: row.get(4)?,
+    })
+}
+
+/// A catalog entry stored in a unit
+pub(crate) struct UnitEntry {
+    pub id: String,
+    pub storage_path: String,
+    pub hash: Vec<u8>,
+}
+
+/// Merkle root over `(storage path, hash)` leaves. Leaves and nodes are hashed with
+/// distinct prefixes, and an odd node is carried to the next level unchanged.
+pub(crate) fn merkle_root<'a, I: Iterator<Item = (&'a str, &'a [u8])>>(leaves: I) -> Hash {
+    let mut level: Vec<Hash> = leaves
+        .map(|(path, hash)| {
+            let mut hasher = blake3::Hasher::new();
+            hasher.update(&[0]);
+            hasher.update(hash);
+            hasher.update(path.as_bytes());
+            hasher.finalize()
+        })
+        .collect();
+    if level.is_empty() {
+        return blake3::hash(&[]);
+    }
+    while level.len() > 1 {
+        level = level.chunks(2)
+            .map(|pair| match pair {
+                [left, right] => {
+                    let mut hasher = blake3::Hasher::new();
+                    hasher.update(&[1]);
+                    hasher.update(left.as_bytes());
+                    hasher.update(right.as_bytes());
+                    hasher.finalize()
+                }
+                [single] => *single,
+                _ => unreachable!(),
+            })
+            .collect();
+    }
+    level[0]
+}
+
+fn unit_root(entries: &[UnitEntry]) -> Hash {
+    merkle_root(entries.iter().map(|e| (e.storage_path.as_str(), e.hash.as_slice())))
+}
+
+/// `(relative path, hex hash)` of the entries of a unit, ordered by path
+fn unit_listing<'a>(unit: &'a StorageUnit, entries: &'a [UnitEntry]) -> impl Iterator<Item = (&'a str, String)> {
+    entries.iter().map(move |e| (&e.storage_path[unit.path.len() + 1..], to_hex(&e.hash)))
+}
+
+/// Check that a unit path is relative and stays inside the repository
+fn check_unit_path(path: &str) -> AppResult<()> {
+    if path.is_empty() || path.ends_with('/')
+        || !Path::new(path).components().all(|c| matches!(c, Component::Normal(_))) {
+        return Err(AppError::new_custom(
+            AppCustomErrorKind::InvalidInput, &format!("invalid storage unit path {:?}", path)));
+    }
+    Ok(())
+}
+
+/// Create the directory of a unit at `path` under the repository `root`
+pub(crate) fn create_unit_dir(root: &Path, path: &str) -> AppResult<()> {
+    check_unit_path(path)?;
+    let unit_dir = root.join(path);
+    fs::create_dir_all(&unit_dir)
+        .map_err(|e| AppError::from_error(e, &format!("creating storage unit directory {}", unit_dir.display())))
+}
+
+impl Repository {
+    /// Location of the blob of an entry
+    pub(crate) fn blob_path(&self, id: &str) -> AppResult<PathBuf> {
+        let storage_path: String = self.database().connection()
+            .query_row("SELECT storage_path FROM main_catalog WHERE id = ?1", [id], |row| row.get(0))
+            .optional()
+            .map_err(|e| AppError::from_error(e, &format!("looking up entry {}", id)))?
+            .ok_or_else(|| AppError::new_custom(
+                AppCustomErrorKind::RepositoryMetadata, &format!("unknown entry {}", id)))?;
+        Ok(self.path().join(storage_path))
+    }
+
+    /// All storage units, ordered by id
+    pub fn storage_units(&self) -> AppResult<Vec<StorageUnit>> {
+        let mut stmt = self.database().connection()
+            .prepare(&format!("SELECT {} FROM storage_unit ORDER BY id", STORAGE_UNIT_COLUMNS))
+            .map_err(|e| AppError::from_error(e, "preparing storage units query"))?;
+        stmt.query_map([], storage_unit_from_row)
+            .and_then(|rows| rows.collect())
+            .map_err(|e| AppError::from_error(e, "reading storage units"))
+    }
+
+    pub fn storage_unit(&self, id: i64) -> AppResult<StorageUnit> {
+        self.database().connection()
+            .query_row(
+                &format!("SELECT {} FROM storage_unit WHERE id = ?1", STORAGE_UNIT_COLUMNS),
+                [id],
+                storage_unit_from_row)
+            .optional()
+            .map_err(|e| AppError::from_error(e, &format!("reading storage unit {}", id)))?
+            .ok_or_else(|| AppError::new_custom(
+                AppCustomErrorKind::StorageUnit, &format!("unknown storage unit {}", id)))
+    }
+
+    /// Create an empty storage unit in directory `path`, relative to the repository
+    /// root. The directory is created along with the catalog record; if it cannot be,
+    /// nothing is recorded.
+    pub fn create_storage_unit(&self, path: &str) -> AppResult<StorageUnit> {
+        freeze::ensure_writable(self.database().connection())?;
+        check_unit_path(path)?;
+        let now = self.timestamp()?;
+        let conn = self.database().connection();
+        let tx = conn.unchecked_transaction()
+            .map_err(|e| AppError::from_error(e, "starting storage unit creation"))?;
+        let overlapping: Option<String> = tx
+            .query_row(
+                "SELECT path FROM storage_unit
+                 WHERE path = ?1 OR substr(?1, 1, length(path) + 1) = path || '/'
+                    OR substr(path, 1, length(?1) + 1) = ?1 || '/'",
+                [path],
+                |row| row.get(0))
+            .optional()
+            .map_err(|e| AppError::from_error(e, &format!("looking up storage units around {}", path)))?;
+        if let Some(other) = overlapping {
+            return Err(AppError::new_custom(
+                AppCustomErrorKind::StorageUnit, &format!("storage unit path {} overlaps unit {}", path, other)));
+        }
+        tx.execute(
+            "INSERT INTO storage_unit (path) VALUES (?1)", [path])
+            .map_err(|e| AppError::from_error(e, &format!("recording storage unit {}", path)))?;
+        let id = tx.last_insert_rowid();
+        audit::record(&tx, &now, "create_storage_unit", None, Some(&format!("storage unit {} at {}", id, path)))?;
+        create_unit_dir(self.path(), path)?;
+        tx.commit().map_err(|e| AppError::from_error(e, "committing storage unit creation"))?;
+        self.storage_unit(id)
+    }
+
+    /// Entries of a unit, ordered by storage path
+    pub(crate) fn storage_unit_entries(&self, unit: &StorageUnit) -> AppResult<Vec<UnitEntry>> {
+        let mut stmt = self.database().connection()
+            .prepare("SELECT id, storage_path, hash FROM main_catalog
+                      WHERE substr(storage_path, 1, length(?1) + 1) = ?1 || '/'
+                      ORDER BY storage_path")
+            .map_err(|e| AppError::from_error(e, "preparing storage unit entries query"))?;
+        stmt.query_map([&unit.path], |row| Ok(UnitEntry { id: row.get(0)?, storage_path: row.get(1)?, hash: row.get(2)? }))
+            .and_then(|rows| rows.collect())
+            .map_err(|e| AppError::from_error(e, &format!("reading entries of storage unit {}", unit.id)))
+    }
+
+    /// Seal a storage unit: write its manifest and record its Merkle root. The catalog
+    /// then refuses new entries in the unit and changes to the path or hash of its
+    /// entries; digests, annotations and relations, which the root does not cover,
+    /// stay writable, and shredding an entry reseals the unit. The blobs themselves are
+    /// only made read-only by freezing the repository.
+    pub fn seal_storage_unit(&self, id: i64) -> AppResult<StorageUnit> {
+        freeze::ensure_writable(self.database().connection())?;
+        let unit = self.storage_unit(id)?;
+        if unit.sealed.is_some() {
+            return Err(AppError::new_custom(
+                AppCustomErrorKind::StorageUnit, &format!("storage unit {} is already sealed", id)));
+        }
+        let entries = self.storage_unit_entries(&unit)?;
+        let root = unit_root(&entries);
+        self.write_manifest(&unit, &entries)?;
+
+        let now = self.timestamp()?;
+        let conn = self.database().connection();
+        let tx = conn.unchecked_transaction()
+            .map_err(|e| AppError::from_error(e, "starting storage unit sealing"))?;
+        tx.execute(
+            "UPDATE storage_unit SET sealed = ?4, manifest_root = ?1, file_count = ?2
+             WHERE id = ?3",
+            params![root.as_bytes().to_vec(), entries.len() as i64, id, now])
+            .map_err(|e| AppError::from_error(e, &format!("sealing storage unit {}", id)))?;
+        audit::record(&tx, &now, "seal", None, Some(&format!("storage unit {} root {}", id, self.render_hash(BLAKE3, root.as_bytes()))))?;
+        tx.commit().map_err(|e| AppError::from_error(e, "committing storage unit sealing"))?;
+        self.telemetry().event("seal", &[("storage_unit", &id.to_string()), ("entries", &entries.len().to_string())]);
+        self.telemetry().counter("afilia_storage_units_sealed_total", 1);
+        self.storage_unit(id)
+    }
+
+    /// Write the manifest of a unit from its catalog entries
+    pub(crate) fn write_manifest(&self, unit: &StorageUnit, entries: &[UnitEntry]) -> AppResult<()> {
+        let unit_dir = self.path().join(&unit.path);
+        let manifest_path = unit_dir.join(MANIFEST_FILE_NAME);
+        let mut manifest = Vec::new();
+        for (relative, hash) in unit_listing(unit, entries) {
+            writeln!(manifest, "{}  {}", hash, relative)
+                .map_err(|e| AppError::from_error(e, "building manifest"))?;
+        }
+        fs::create_dir_all(&unit_dir)
+            .and_then(|_| fs::write(&manifest_path, manifest))
+            .map_err(|e| AppError::from_error(e, &format!("writing {}", manifest_path.display())))
+    }
+
+    /// Sealed unit holding a storage path, if any
+    pub(crate) fn sealed_unit_of(&self, storage_path: &str) -> AppResult<Option<StorageUnit>> {
+        self.database().connection()
+            .query_row(
+                &format!("SELECT {} FROM storage_unit
+                          WHERE sealed IS NOT NULL AND substr(?1, 1, length(path) + 1) = path || '/'",
+                         STORAGE_UNIT_COLUMNS),
+                [storage_path],
+                storage_unit_from_row)
+            .optional()
+            .map_err(|e| AppError::from_error(e, &format!("looking up storage unit of {}", storage_path)))
+    }
+
+    /// Record and audit the root of a sealed unit after entries were removed from it,
+    /// within the transaction of the removal. The manifest must be rewritten once it is
+    /// committed.
+    pub(crate) fn reseal_storage_unit(&self, conn: &Connection, now: &str, unit: &StorageUnit) -> AppResult<Vec<UnitEntry>> {
+        let entries = self.storage_unit_entries(unit)?;
+        let root = unit_root(&entries);
+        conn.execute(
+            "UPDATE storage_unit SET manifest_root = ?1, file_count = ?2 WHERE id = ?3",
+            params![root.as_bytes().to_vec(), entries.len() as i64, unit.id])
+            .map_err(|e| AppError::from_error(e, &format!("updating root of storage unit {}", unit.id)))?;
+        audit::record(conn, now, "reseal", None, Some(&format!("storage unit {} root {}", unit.id, self.render_hash(BLAKE3, root.as_bytes()))))?;
+        Ok(entries)
+    }
+
+    /// Check the catalog of every sealed unit against its recorded root. A unit whose
+    /// root matches is confirmed as a whole; only the units whose root differs have
+    /// their blobs re-hashed and are compared entry by entry with their manifest.
+    pub fn verify_storage_units(&self) -> AppResult<Vec<UnitCheck>> {
+        self.check_storage_units(false)
+    }
+
+    /// Like `verify_storage_units`, but re-hash the blobs of every sealed unit, to catch
+    /// blobs altered on disk behind an unchanged catalog
+    pub fn scan_storage_units(&self) -> AppResult<Vec<UnitCheck>> {
+        self.check_storage_units(true)
+    }
+
+    fn check_storage_units(&self, full: bool) -> AppResult<Vec<UnitCheck>> {
+        let mut checks = Vec::new();
+        for unit in self.storage_units()? {
+            let Some(recorded_root) = unit.manifest_root.as_deref() else {
+                continue;
+            };
+            let entries = self.storage_unit_entries(&unit)?;
+            let changed = unit_root(&entries).as_bytes()[..] != recorded_root[..];
+            let mut mismatched = Vec::new();
+            if changed || full {
+                mismatched.extend(self.altered_blobs(&unit, &entries)?);
+            }
+            if changed {
+                mismatched.extend(self.manifest_mismatches(&unit, &entries)?);
+            }
+            mismatched.sort();
+            mismatched.dedup();
+            checks.push(UnitCheck { unit_id: unit.id, mismatched });
+        }
+        Ok(checks)
+    }
+
+    /// Paths, relative to the unit, of the blobs missing or not matching their hash
+    fn altered_blobs(&self, unit: &StorageUnit, entries: &[UnitEntry]) -> AppResult<Vec<String>> {
+        let mut altered = Vec::new();
+        for (entry, (relative, _)) in entries.iter().zip(unit_listing(unit, entries)) {
+            let actual = hash_blob(&self.path().join(&entry.storage_path))?;
+            if actual.as_ref().map(|hash| &hash.as_bytes()[..]) != Some(entry.hash.as_slice()) {
+                altered.push(relative.to_string());
+            }
+        }
+        Ok(altered)
+    }
+
+    /// Paths, relative to the unit, whose catalog record differs from the unit manifest,
+    /// or the manifest itself when every record matches it
+    fn manifest_mismatches(&self, unit: &StorageUnit, entries: &[UnitEntry]) -> AppResult<Vec<String>> {
+        let manifest_path = self.path().join(&unit.path).join(MANIFEST_FILE_NAME);
+        let manifest = fs::read_to_string(&manifest_path)
+            .map_err(|e| AppError::from_error(e, &format!("reading {}", manifest_path.display())))?;
+        let mut listed: BTreeMap<&str, &str> = manifest.lines()
+            .filter_map(|line| line.split_once("  ").map(|(hash, relative)| (relative, hash)))
+            .collect();
+        let mut mismatched: Vec<String> = unit_listing(unit, entries)
+            .filter(|(relative, hash)| listed.remove(relative) != Some(hash.as_str()))
+            .map(|(relative, _)| relative.to_string())
+            .collect();
+        mismatched.extend(listed.into_keys().map(String::from));
+        if mismatched.is_empty() {
+            mismatched.push(MANIFEST_FILE_NAME.to_string());
+        }
+        Ok(mismatched)
+    }
+}
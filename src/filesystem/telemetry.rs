@@ -0,0 +1,78 @@
+//! Operational signals of a repository. The library reports events (an operation
+//! completed) and metrics to the telemetry sink of the repository handle, so an embedder
+//! can route them to its own observability stack. The default sink drops everything.
+//!
+//! Signals cover the long-running and destructive operations: verification, shredding,
+//! sealing, freezing and thawing, and checksum imports. Other mutations are recorded in
+//! the audit log only. No tracing sink is provided, as the crate does not depend on
+//! `tracing`; an embedder implements `event` with its own spans or events.
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+/// Receiver of the signals of a repository. Every method does nothing by default, so
+/// that a sink only implements what it uses.
+pub trait TelemetrySink: Send + Sync {
+    /// Operation `name` completed, described by `fields`
+    fn event(&self, _name: &str, _fields: &[(&str, &str)]) {}
+
+    /// Increase the counter `name` by `value`
+    fn counter(&self, _name: &str, _value: u64) {}
+
+    /// Set the gauge `name` to `value`
+    fn gauge(&self, _name: &str, _value: f64) {}
+}
+
+/// A sink dropping every signal
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoopTelemetry;
+
+impl TelemetrySink for NoopTelemetry {}
+
+#[derive(Debug, Clone, Copy)]
+enum Metric {
+    Counter(u64),
+    Gauge(f64),
+}
+
+/// A sink keeping the latest value of every metric, to be scraped in the Prometheus
+/// text exposition format. Events are not kept.
+#[derive(Debug, Default)]
+pub struct PrometheusTelemetry {
+    metrics: Mutex<BTreeMap<String, Metric>>,
+}
+
+impl PrometheusTelemetry {
+    pub fn new() -> PrometheusTelemetry {
+        PrometheusTelemetry::default()
+    }
+
+    /// Current value of every metric, sorted by name
+    pub fn render(&self) -> String {
+        let metrics = self.metrics.lock().unwrap_or_else(|e| e.into_inner());
+        let mut text = String::new();
+        for (name, metric) in metrics.iter() {
+            let _ = match metric {
+                Metric::Counter(value) => write!(text, "# TYPE {} counter\n{} {}\n", name, name, value),
+                Metric::Gauge(value) => write!(text, "# TYPE {} gauge\n{} {}\n", name, name, value),
+            };
+        }
+        text
+    }
+}
+
+impl TelemetrySink for PrometheusTelemetry {
+    fn counter(&self, name: &str, value: u64) {
+        let mut metrics = self.metrics.lock().unwrap_or_else(|e| e.into_inner());
+        let metric = metrics.entry(name.to_string()).or_insert(Metric::Counter(0));
+        *metric = match *metric {
+            Metric::Counter(total) => Metric::Counter(total.saturating_add(value)),
+            Metric::Gauge(_) => Metric::Counter(value),
+        };
+    }
+
+    fn gauge(&self, name: &str, value: f64) {
+        let mut metrics = self.metrics.lock().unwrap_or_else(|e| e.into_inner());
+        metrics.insert(name.to_string(), Metric::Gauge(value));
+    }
+}
@@ -0,0 +1,147 @@
+//! Export of a sealed storage unit to a tape (LTFS) friendly layout: one flat directory
+//! of blobs named by hash, written sequentially, a manifest and an index at known paths.
+//! The export is self-describing and can be verified without the repository.
+//!
+//! ```text
+//! <dest>/AFILIA_UNIT.json   index: repository, unit, Merkle root and entries
+//! <dest>/MANIFEST.b3        `b3sum -c` list of the blobs
+//! <dest>/blobs/<hash>       blob content
+//! ```
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::filesystem::digest::BLAKE3;
+use crate::filesystem::error::{AppCustomErrorKind, AppError, AppResult};
+use crate::filesystem::hash_format::{decode_any, from_hex, to_hex};
+use crate::filesystem::repository::Repository;
+use crate::filesystem::storage::{merkle_root, MANIFEST_FILE_NAME};
+use crate::filesystem::verify::{hash_blob, hash_file, VerifyReport};
+use crate::fsutil::safe_copy;
+
+pub const UNIT_INDEX_FILE_NAME: &str = "AFILIA_UNIT.json";
+const BLOBS_DIR: &str = "blobs";
+
+#[derive(Serialize, Deserialize)]
+struct UnitIndex {
+    repository: Uuid,
+    unit_id: i64,
+    unit_path: String,
+    manifest_root: String,
+    entries: Vec<UnitIndexEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct UnitIndexEntry {
+    id: String,
+    storage_path: String,
+    hash: String,
+}
+
+fn unit_error(msg: &str) -> AppError {
+    AppError::new_custom(AppCustomErrorKind::StorageUnit, msg)
+}
+
+/// A unit or an export whose content no longer matches its recorded hashes
+fn corruption_error(msg: &str) -> AppError {
+    AppError::new_custom(AppCustomErrorKind::Verify, msg)
+}
+
+impl Repository {
+    /// Export a sealed storage unit to `dest`. Each blob is hashed while copied, and the
+    /// export fails if the unit no longer matches the root recorded when it was sealed.
+    pub fn export_sealed_unit(&self, id: i64, dest: &Path) -> AppResult<()> {
+        let unit = self.storage_unit(id)?;
+        let recorded_root = unit.manifest_root()
+            .ok_or_else(|| unit_error(&format!("storage unit {} is not sealed", id)))?;
+        let entries = self.storage_unit_entries(&unit)?;
+        let root = merkle_root(entries.iter().map(|e| (e.storage_path.as_str(), e.hash.as_slice())));
+        if root.as_bytes()[..] != recorded_root[..] {
+            return Err(corruption_error(&format!("storage unit {} changed since it was sealed", id)));
+        }
+
+        let blobs_dir = dest.join(BLOBS_DIR);
+        fs::create_dir_all(&blobs_dir)
+            .map_err(|e| AppError::from_error(e, &format!("creating {}", blobs_dir.display())))?;
+        let mut manifest = String::new();
+        let mut written = HashSet::new();
+        for entry in entries.iter() {
+            let name = to_hex(&entry.hash);
+            if !written.insert(name.clone()) {
+                continue;
+            }
+            let blob_path = blobs_dir.join(&name);
+            let expected = <[u8; blake3::OUT_LEN]>::try_from(entry.hash.as_slice())
+                .map(blake3::Hash::from_bytes)
+                .map_err(|_| corruption_error(&format!("invalid hash of entry {}", entry.id)))?;
+            // a blob left by a previous export is kept only if still intact
+            if hash_blob(&blob_path)? != Some(expected) {
+                safe_copy(&self.path().join(&entry.storage_path), &blob_path, Some(&expected))?;
+            }
+            manifest.push_str(&format!("{}  {}/{}\n", name, BLOBS_DIR, name));
+        }
+        let manifest_path = dest.join(MANIFEST_FILE_NAME);
+        fs::write(&manifest_path, manifest)
+            .map_err(|e| AppError::from_error(e, &format!("writing {}", manifest_path.display())))?;
+
+        let index = UnitIndex {
+            repository: *self.uuid(),
+            unit_id: id,
+            unit_path: unit.path().to_string(),
+            manifest_root: self.render_hash(BLAKE3, root.as_bytes()),
+            entries: entries.into_iter()
+                .map(|e| UnitIndexEntry { hash: to_hex(&e.hash), id: e.id, storage_path: e.storage_path })
+                .collect(),
+        };
+        let index_path = dest.join(UNIT_INDEX_FILE_NAME);
+        let content = serde_json::to_string_pretty(&index)
+            .map_err(|e| AppError::from_error(e, "serializing unit index"))?;
+        fs::write(&index_path, content)
+            .map_err(|e| AppError::from_error(e, &format!("writing {}", index_path.display())))
+    }
+}
+
+/// Read back an exported unit and re-hash its blobs. The index itself is checked
+/// against its Merkle root first; entries are reported by id.
+pub fn verify_unit_export(dest: &Path) -> AppResult<VerifyReport> {
+    let index_path = dest.join(UNIT_INDEX_FILE_NAME);
+    let content = fs::read_to_string(&index_path)
+        .map_err(|e| AppError::from_error(e, &format!("reading {}", index_path.display())))?;
+    let index: UnitIndex = serde_json::from_str(&content)
+        .map_err(|e| AppError::from_error(e, &format!("parsing {}", index_path.display())))?;
+
+    let hashes = index.entries.iter()
+        .map(|e| from_hex(&e.hash).ok_or_else(|| corruption_error(&format!("invalid hash of entry {}", e.id))))
+        .collect::<AppResult<Vec<Vec<u8>>>>()?;
+    let root = merkle_root(index.entries.iter().zip(hashes.iter())
+        .map(|(e, hash)| (e.storage_path.as_str(), hash.as_slice())));
+    if decode_any(BLAKE3, &index.manifest_root).as_deref() != Some(&root.as_bytes()[..]) {
+        return Err(corruption_error(&format!("{} does not match its manifest root", index_path.display())));
+    }
+
+    let mut report = VerifyReport {
+        total: index.entries.len(),
+        checked: index.entries.len(),
+        corrupted: vec![],
+        missing: vec![],
+    };
+    for entry in index.entries {
+        let blob_path = dest.join(BLOBS_DIR).join(&entry.hash);
+        let file = match File::open(&blob_path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                report.missing.push(entry.id);
+                continue;
+            }
+            Err(err) => return Err(AppError::from_error(err, &format!("opening {}", blob_path.display()))),
+        };
+        let hash = hash_file(file)
+            .map_err(|e| AppError::from_error(e, &format!("hashing {}", blob_path.display())))?;
+        if hash.to_hex().as_str() != entry.hash {
+            report.corrupted.push(entry.id);
+        }
+    }
+    Ok(report)
+}
@@ -0,0 +1,220 @@
+//! Verification of stored blobs against the hashes recorded in the catalog. A full scan
+//! re-hashes every entry; a sample scan re-hashes a seeded random subset and estimates
+//! the corruption rate of the whole repository.
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use rusqlite::{params, OptionalExtension};
+use crate::filesystem::error::{AppCustomErrorKind, AppError, AppResult};
+use crate::filesystem::repository::Repository;
+
+/// z-score of the two-sided 95% confidence interval
+const CONFIDENCE_Z: f64 = 1.96;
+
+/// Which entries to verify
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
+pub enum VerifyMode {
+    /// Every catalog entry
+    Full,
+    /// A random `fraction` (in `(0, 1]`) of the entries; the same seed picks the same
+    /// entries on an unchanged catalog
+    #[non_exhaustive]
+    Sample { fraction: f64, seed: u64 },
+}
+
+impl VerifyMode {
+    /// Sample mode, see [`VerifyMode::Sample`]
+    pub fn sample(fraction: f64, seed: u64) -> VerifyMode {
+        VerifyMode::Sample { fraction, seed }
+    }
+}
+
+/// Outcome of a verification run
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifyReport {
+    pub(crate) total: usize,
+    pub(crate) checked: usize,
+    pub(crate) corrupted: Vec<String>,
+    pub(crate) missing: Vec<String>,
+}
+
+impl VerifyReport {
+    /// Number of entries in the catalog
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    /// Number of entries actually re-hashed
+    pub fn checked(&self) -> usize {
+        self.checked
+    }
+
+    /// Ids of entries whose blob does not match the recorded hash
+    pub fn corrupted(&self) -> &[String] {
+        &self.corrupted
+    }
+
+    /// Ids of entries whose blob could not be found
+    pub fn missing(&self) -> &[String] {
+        &self.missing
+    }
+
+    /// Proportion of checked entries which failed verification
+    pub fn corruption_rate(&self) -> f64 {
+        if self.checked == 0 {
+            return 0.0;
+        }
+        (self.corrupted.len() + self.missing.len()) as f64 / self.checked as f64
+    }
+
+    /// 95% confidence bounds of the repository corruption rate (Wilson score interval).
+    /// They collapse to the observed rate when every entry was checked.
+    pub fn confidence_interval(&self) -> (f64, f64) {
+        let rate = self.corruption_rate();
+        if self.checked == 0 || self.checked == self.total {
+            return (rate, rate);
+        }
+        let n = self.checked as f64;
+        let z2 = CONFIDENCE_Z * CONFIDENCE_Z;
+        let denominator = 1.0 + z2 / n;
+        let center = (rate + z2 / (2.0 * n)) / denominator;
+        let half = CONFIDENCE_Z * (rate * (1.0 - rate) / n + z2 / (4.0 * n * n)).sqrt() / denominator;
+        ((center - half).max(0.0), (center + half).min(1.0))
+    }
+}
+
+/// Result of re-verifying a single entry
+#[derive(Debug, Clone, PartialEq)]
+pub struct EntryVerification {
+    id: String,
+    expected: Vec<u8>,
+    actual: Option<Vec<u8>>,
+}
+
+impl EntryVerification {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Hash recorded in the catalog
+    pub fn expected(&self) -> &[u8] {
+        &self.expected
+    }
+
+    /// Hash of the blob as read now, `None` when the blob is missing
+    pub fn actual(&self) -> Option<&[u8]> {
+        self.actual.as_deref()
+    }
+
+    pub fn is_missing(&self) -> bool {
+        self.actual.is_none()
+    }
+
+    /// Whether the blob matches the catalog
+    pub fn is_intact(&self) -> bool {
+        self.actual.as_deref() == Some(self.expected.as_slice())
+    }
+}
+
+/// BLAKE3 hash of a file content
+pub(crate) fn hash_file(mut file: File) -> io::Result<blake3::Hash> {
+    let mut hasher = blake3::Hasher::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(hasher.finalize())
+}
+
+/// splitmix64, enough to draw reproducible samples and seeded ids
+pub(crate) struct SampleRng(pub(crate) u64);
+
+impl SampleRng {
+    pub(crate) fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+/// Keep `count` elements picked by a partial Fisher-Yates shuffle
+fn sample<T>(mut items: Vec<T>, count: usize, seed: u64) -> Vec<T> {
+    let mut rng = SampleRng(seed);
+    for i in 0..count {
+        let j = i + (rng.next() % (items.len() - i) as u64) as usize;
+        items.swap(i, j);
+    }
+    items.truncate(count);
+    items
+}
+
+/// Hash of a blob, `None` if it does not exist
+pub(crate) fn hash_blob(path: &Path) -> AppResult<Option<blake3::Hash>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(AppError::from_error(err, &format!("opening {}", path.display()))),
+    };
+    hash_file(file)
+        .map(Some)
+        .map_err(|e| AppError::from_error(e, &format!("hashing {}", path.display())))
+}
+
+impl Repository {
+    /// Re-hash the blob of a single entry, e.g. before relying on it
+    pub fn verify_entry(&self, id: &str) -> AppResult<EntryVerification> {
+        let (expected, storage_path): (Vec<u8>, String) = self.database().connection()
+            .query_row("SELECT hash, storage_path FROM main_catalog WHERE id = ?1", [id],
+                       |row| Ok((row.get(0)?, row.get(1)?)))
+            .optional()
+            .map_err(|e| AppError::from_error(e, &format!("looking up entry {}", id)))?
+            .ok_or_else(|| AppError::new_custom(
+                AppCustomErrorKind::RepositoryMetadata, &format!("unknown entry {}", id)))?;
+        let actual = hash_blob(&self.path().join(storage_path))?;
+        Ok(EntryVerification { id: id.to_string(), expected, actual: actual.map(|h| h.as_bytes().to_vec()) })
+    }
+
+    /// Re-hash the blobs of the entries selected by `mode` and compare them with the
+    /// catalog
+    pub fn verify(&self, mode: VerifyMode) -> AppResult<VerifyReport> {
+        let mut stmt = self.database().connection()
+            .prepare("SELECT id, hash, storage_path FROM main_catalog ORDER BY id")
+            .map_err(|e| AppError::from_error(e, "preparing verification query"))?;
+        let entries: Vec<(String, Vec<u8>, String)> = stmt
+            .query_map(params![], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .and_then(|rows| rows.collect())
+            .map_err(|e| AppError::from_error(e, "reading catalog"))?;
+        let total = entries.len();
+
+        let entries = match mode {
+            VerifyMode::Full => entries,
+            VerifyMode::Sample { fraction, seed } => {
+                if !(fraction > 0.0 && fraction <= 1.0) {
+                    return Err(AppError::new_custom(
+                        AppCustomErrorKind::InvalidInput,
+                        &format!("sample fraction {} is not in (0, 1]", fraction)));
+                }
+                let count = ((total as f64 * fraction).ceil() as usize).min(total);
+                sample(entries, count, seed)
+            }
+        };
+
+        let mut report = VerifyReport { total, checked: entries.len(), corrupted: vec![], missing: vec![] };
+        for (id, hash, storage_path) in entries {
+            match hash_blob(&self.path().join(&storage_path))? {
+                None => report.missing.push(id),
+                Some(actual) if actual.as_bytes()[..] != hash[..] => report.corrupted.push(id),
+                Some(_) => {}
+            }
+        }
+        self.telemetry().event("verify", &[
+            ("checked", &report.checked.to_string()),
+            ("corrupted", &report.corrupted.len().to_string()),
+            ("missing", &report.missing.len().to_string()),
+        ]);
+        self.telemetry().counter("afilia_entries_verified_total", report.checked as u64);
+        self.telemetry().counter("afilia_entries_corrupted_total", report.corrupted.len() as u64);
+        self.telemetry().counter("afilia_entries_missing_total", report.missing.len() as u64);
+        Ok(report)
+    }
+}
@@ -0,0 +1,91 @@
+//! Filesystem building blocks used by the library, public because tools around a
+//! repository keep needing the same ones.
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+use crate::filesystem::error::{AppCustomErrorKind, AppError, AppResult};
+
+const COPY_BUFFER_SIZE: usize = 1024 * 1024;
+const COPY_ATTEMPTS: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Copy `src` to `dst` so that `dst` either does not change or holds a complete,
+/// durable copy. The content is hashed while written to a partial file next to `dst`,
+/// synced, checked against `expected` when given, then renamed over `dst`. Interrupted
+/// or timed out I/O and hash mismatches, which flaky media produce, are retried a few
+/// times. Returns the BLAKE3 hash of the copy.
+pub fn safe_copy(src: &Path, dst: &Path, expected: Option<&blake3::Hash>) -> AppResult<blake3::Hash> {
+    let partial = partial_path(dst)?;
+    let mut attempt = 1;
+    let hash = loop {
+        let result = copy_hashed(src, &partial);
+        let retry = attempt < COPY_ATTEMPTS;
+        match result {
+            Ok(hash) if expected.is_none_or(|e| *e == hash) => break hash,
+            Ok(_) if retry => {}
+            Err(err) if retry && is_transient(&err) => {}
+            Ok(hash) => {
+                let _ = fs::remove_file(&partial);
+                return Err(AppError::new_custom(AppCustomErrorKind::SafeCopy, &format!(
+                    "copy of {} hashes to {} instead of {}",
+                    src.display(), hash.to_hex(), expected.map(|e| e.to_hex()).unwrap_or_default())));
+            }
+            Err(err) => {
+                let _ = fs::remove_file(&partial);
+                return Err(AppError::from_error(err, &format!("copying {} to {}", src.display(), dst.display())));
+            }
+        }
+        thread::sleep(RETRY_DELAY * attempt);
+        attempt += 1;
+    };
+    fs::rename(&partial, dst)
+        .and_then(|_| sync_parent(dst))
+        .map_err(|e| AppError::from_error(e, &format!("moving copy into {}", dst.display())))?;
+    Ok(hash)
+}
+
+fn partial_path(dst: &Path) -> AppResult<PathBuf> {
+    let name = dst.file_name().ok_or_else(|| AppError::new_custom(
+        AppCustomErrorKind::SafeCopy, &format!("{} is not a file path", dst.display())))?;
+    let mut partial = std::ffi::OsString::from(".");
+    partial.push(name);
+    partial.push(".partial");
+    Ok(dst.with_file_name(partial))
+}
+
+fn is_transient(err: &io::Error) -> bool {
+    matches!(err.kind(), io::ErrorKind::Interrupted | io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock)
+}
+
+fn copy_hashed(src: &Path, dst: &Path) -> io::Result<blake3::Hash> {
+    let mut input = File::open(src)?;
+    let mut output = File::create(dst)?;
+    let mut hasher = blake3::Hasher::new();
+    let mut buffer = vec![0u8; COPY_BUFFER_SIZE];
+    loop {
+        let read = input.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        output.write_all(&buffer[..read])?;
+    }
+    output.sync_all()?;
+    Ok(hasher.finalize())
+}
+
+/// Make a rename durable. Directories cannot be opened for syncing on every platform.
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => File::open(parent)?.sync_all(),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
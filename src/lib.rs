@@ -1,5 +1,8 @@
 pub mod filesystem;
 pub mod error;
+pub mod fsutil;
+#[cfg(feature = "testkit")]
+pub mod testkit;
 
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,78 @@
+//! Test helpers, enabled by the `testkit` feature. Repositories can be created
+//! deterministically, and damaged in a deterministic way, so that verification and
+//! recovery code can be exercised by integration tests against known faults.
+use std::fs::{self, OpenOptions};
+use std::sync::{Arc, Mutex};
+use rusqlite::OptionalExtension;
+use uuid::Uuid;
+use crate::filesystem::clock::Clock;
+use crate::filesystem::error::{AppCustomErrorKind, AppError, AppResult};
+use crate::filesystem::repository::{IdSource, Repository, SEALED_UNIT_TRIGGERS, SIGN_FILE_NAME};
+use crate::filesystem::verify::SampleRng;
+
+/// Create a repository with a fixed UUID, timestamps taken from `clock` and the ids it
+/// generates drawn from `seed`. The same calls on the same inputs then produce
+/// byte-identical repositories.
+pub fn create_deterministic(path: &str, name: &str, payload: &str, uuid: Uuid,
+                            clock: Arc<dyn Clock>, seed: u64) -> AppResult<Repository> {
+    Repository::create_with(path, uuid, name, payload, clock, IdSource::Seeded(Mutex::new(SampleRng(seed))))
+}
+
+/// Invert the bits of the byte at `offset` in the blob of an entry
+pub fn corrupt_blob(repository: &Repository, id: &str, offset: usize) -> AppResult<()> {
+    let path = repository.blob_path(id)?;
+    let mut content = fs::read(&path)
+        .map_err(|e| AppError::from_error(e, &format!("reading {}", path.display())))?;
+    if offset >= content.len() {
+        return Err(AppError::new_custom(
+            AppCustomErrorKind::RepositoryStructure,
+            &format!("offset {} is past the end of {}", offset, path.display())));
+    }
+    content[offset] = !content[offset];
+    fs::write(&path, content)
+        .map_err(|e| AppError::from_error(e, &format!("writing {}", path.display())))
+}
+
+/// Cut the blob of an entry down to `len` bytes
+pub fn truncate_blob(repository: &Repository, id: &str, len: u64) -> AppResult<()> {
+    let path = repository.blob_path(id)?;
+    OpenOptions::new().write(true).open(&path)
+        .and_then(|file| file.set_len(len))
+        .map_err(|e| AppError::from_error(e, &format!("truncating {}", path.display())))
+}
+
+/// Invert the first byte of the hash recorded for an entry, leaving its blob intact.
+/// This works on entries of sealed units too, bypassing the catalog triggers.
+pub fn flip_catalog_row(repository: &Repository, id: &str) -> AppResult<()> {
+    let mut hash: Vec<u8> = repository.database().connection()
+        .query_row("SELECT hash FROM main_catalog WHERE id = ?1", [id], |row| row.get(0))
+        .optional()
+        .map_err(|e| AppError::from_error(e, &format!("looking up entry {}", id)))?
+        .ok_or_else(|| AppError::new_custom(
+            AppCustomErrorKind::RepositoryMetadata, &format!("unknown entry {}", id)))?;
+    if let Some(byte) = hash.first_mut() {
+        *byte = !*byte;
+    }
+    let conn = repository.database().connection();
+    let tx = conn.unchecked_transaction()
+        .map_err(|e| AppError::from_error(e, "starting catalog row damage"))?;
+    tx.execute("DROP TRIGGER IF EXISTS sealed_unit_update", [])
+        .and_then(|_| tx.execute("UPDATE main_catalog SET hash = ?1 WHERE id = ?2", rusqlite::params![hash, id]))
+        .map_err(|e| AppError::from_error(e, &format!("damaging catalog row of {}", id)))?;
+    for sql in SEALED_UNIT_TRIGGERS {
+        tx.execute(sql, []).map_err(|e| AppError::from_error(e, "restoring catalog triggers"))?;
+    }
+    tx.commit().map_err(|e| AppError::from_error(e, "committing catalog row damage"))
+}
+
+/// Overwrite the middle of the sign file so that it no longer parses
+pub fn damage_sign_file(repository: &Repository) -> AppResult<()> {
+    let path = repository.path().join(SIGN_FILE_NAME);
+    let mut content = fs::read(&path)
+        .map_err(|e| AppError::from_error(e, &format!("reading {}", path.display())))?;
+    let middle = content.len() / 2;
+    content.truncate(middle);
+    content.extend_from_slice(b"\0\0\0\0");
+    fs::write(&path, content)
+        .map_err(|e| AppError::from_error(e, &format!("writing {}", path.display())))
+}
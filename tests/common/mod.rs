@@ -0,0 +1,27 @@
+#![allow(dead_code)]
+use std::fs;
+use std::path::{Path, PathBuf};
+use rusqlite::Connection;
+
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("afilia_{}_{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+pub fn insert_entry(dir: &Path, id: &str, content: &[u8], storage_path: &str) {
+    let conn = Connection::open(dir.join("afilia_repo.db")).unwrap();
+    conn.execute(
+        "INSERT INTO main_catalog (id, hash, storage_path, size) VALUES (?1, ?2, ?3, ?4)",
+        rusqlite::params![id, blake3::hash(content).as_bytes().to_vec(), storage_path, content.len()],
+    ).unwrap();
+}
+
+/// Write a blob under the repository and catalog it
+pub fn store_entry(dir: &Path, id: &str, content: &[u8]) {
+    let storage_path = format!("objects/{}", id);
+    fs::create_dir_all(dir.join("objects")).unwrap();
+    fs::write(dir.join(&storage_path), content).unwrap();
+    insert_entry(dir, id, content, &storage_path);
+}
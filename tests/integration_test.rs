@@ -1,7 +1,966 @@
-use afilia;
+mod common;
+
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use afilia::filesystem::annotation::ReviewState;
+use afilia::filesystem::changes::{ChangeKind, SequenceNumber};
+use afilia::filesystem::checksum::ChecksumFormat;
+use afilia::filesystem::clock::TestClock;
+use afilia::filesystem::doctor::CheckStatus;
+use afilia::filesystem::error::{AppCustomErrorKind, InternalError};
+use afilia::filesystem::hash_format::HashFormat;
+use afilia::filesystem::metadata_file::MappingFormat;
+use afilia::filesystem::passport::Passport;
+use afilia::filesystem::queue::QueueStatus;
+use afilia::filesystem::recovery_kit::RECOVERY_INDEX_FILE_NAME;
+use afilia::filesystem::relation::{Direction, RelationKind};
+use afilia::filesystem::repository::Repository;
+use afilia::filesystem::storage::{DEFAULT_STORAGE_ROOT, MANIFEST_FILE_NAME};
+use afilia::filesystem::telemetry::{PrometheusTelemetry, TelemetrySink};
+use afilia::filesystem::unit_export::verify_unit_export;
+use afilia::filesystem::verify::VerifyMode;
+use afilia::fsutil::safe_copy;
+use common::{insert_entry, store_entry, test_dir};
 
 #[test]
 fn it_adds_two() {
     let result = 2 + 2;
     assert_eq!(result, 4);
 }
+
+#[test]
+fn it_creates_repository() {
+    let dir = test_dir("create");
+    let repository = Repository::create(dir.to_str().unwrap(), "test", "payload").unwrap();
+    assert_eq!(repository.path(), dir.as_path());
+    assert!(dir.join(".afilia_repo").is_file());
+    assert!(dir.join("afilia_repo.db").is_file());
+}
+
+#[test]
+fn it_leases_writer() {
+    let dir = test_dir("lease");
+    let path = dir.to_str().unwrap();
+    let daemon = Repository::create(path, "test", "payload").unwrap();
+    let cli = Repository::open(path).unwrap();
+    assert_eq!(daemon.uuid(), cli.uuid());
+
+    let lease = daemon.acquire_lease(Duration::from_secs(60)).unwrap();
+    assert!(cli.acquire_lease(Duration::from_secs(60)).is_err());
+    let lease = daemon.renew_lease(&lease, Duration::from_secs(120)).unwrap();
+    daemon.release_lease(lease).unwrap();
+
+    // A zero ttl lease lapses immediately, as if its holder had crashed
+    let crashed = cli.acquire_lease(Duration::from_secs(0)).unwrap();
+    let taken = daemon.acquire_lease(Duration::from_secs(60)).unwrap();
+    assert!(cli.renew_lease(&crashed, Duration::from_secs(60)).is_err());
+    assert_ne!(taken.holder(), crashed.holder());
+    assert_eq!(daemon.renew_lease(&taken, Duration::MAX).unwrap_err().custom_kind(), Some(&AppCustomErrorKind::RepositoryLease));
+    assert!(cli.acquire_lease(Duration::MAX).is_err());
+}
+
+#[test]
+fn it_exports_checksums() {
+    let dir = test_dir("checksums");
+    let repository = Repository::create(dir.to_str().unwrap(), "test", "payload").unwrap();
+    insert_entry(&dir, "e1", b"hello", "objects/00/e1");
+    let hash = blake3::hash(b"hello").to_hex();
+
+    let mut gnu = Vec::new();
+    assert_eq!(repository.export_checksums(&mut gnu, ChecksumFormat::Gnu, "blake3").unwrap(), 1);
+    assert_eq!(String::from_utf8(gnu).unwrap(), format!("{}  objects/00/e1\n", hash));
+
+    let mut bsd = Vec::new();
+    repository.export_checksums(&mut bsd, ChecksumFormat::Bsd, "blake3").unwrap();
+    assert_eq!(String::from_utf8(bsd).unwrap(), format!("BLAKE3 (objects/00/e1) = {}\n", hash));
+
+    insert_entry(&dir, "e2", b"odd", "objects/a\\b\nc");
+    repository.record_digest("e1", "sha256", &[0xab; 32]).unwrap();
+    let mut sha256 = Vec::new();
+    assert_eq!(repository.export_checksums(&mut sha256, ChecksumFormat::Bsd, "sha256").unwrap(), 1);
+    assert_eq!(String::from_utf8(sha256).unwrap(), format!("SHA256 (objects/00/e1) = {}\n", "ab".repeat(32)));
+    let mut escaped = Vec::new();
+    repository.export_checksums(&mut escaped, ChecksumFormat::Gnu, "blake3").unwrap();
+    let escaped = String::from_utf8(escaped).unwrap();
+    assert!(escaped.ends_with(&format!("\\{}  objects/a\\\\b\\nc\n", blake3::hash(b"odd").to_hex())));
+    repository.import_checksums(escaped.as_bytes(), "blake3").unwrap();
+    assert_eq!(repository.provisional_entries().unwrap()[1].path(), "objects/a\\b\nc");
+
+    let mut hashdeep = Vec::new();
+    assert!(repository.export_checksums(&mut hashdeep, ChecksumFormat::Hashdeep, "blake3").is_err());
+    hashdeep.clear();
+    assert_eq!(repository.export_checksums(&mut hashdeep, ChecksumFormat::Hashdeep, "sha256").unwrap(), 1);
+    let hashdeep = String::from_utf8(hashdeep).unwrap();
+    assert_eq!(hashdeep, format!("%%%% HASHDEEP-1.0\n%%%% size,sha256,filename\n## Written by afilia\n##\n5,{},objects/00/e1\n", "ab".repeat(32)));
+    repository.import_checksums(hashdeep.as_bytes(), "sha256").unwrap();
+    assert!(repository.provisional_entries().unwrap().iter().any(|e| e.size() == Some(5) && e.digest("sha256").is_some()));
+}
+
+#[test]
+fn it_imports_checksum_lists() {
+    let dir = test_dir("import_checksums");
+    let repository = Repository::create(dir.to_str().unwrap(), "test", "payload").unwrap();
+    let sha256sum = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824  photos/hello.txt\n";
+    assert_eq!(repository.import_checksums(sha256sum.as_bytes(), "sha256").unwrap(), 1);
+    let hashdeep = "%%%% HASHDEEP-1.0\n\
+                    %%%% size,md5,filename\n\
+                    ## Invoked from: /home/user\n\
+                    ##\n\
+                    5,5d41402abc4b2a76b9719d911017c592,docs/a,b.txt\n";
+    assert_eq!(repository.import_checksums(hashdeep.as_bytes(), "sha256").unwrap(), 1);
+    assert!(repository.import_checksums("not a checksum\n".as_bytes(), "sha256").is_err());
+    let err = repository.import_checksums("SHA256 (a.txt) = 00ff\n".as_bytes(), "md5").unwrap_err();
+    assert_eq!(err.msg(), "line 1: sha256 digest of 2 bytes, expected 32");
+    assert!(repository.import_checksums("00ff  a.txt\n".as_bytes(), "md5").is_err());
+
+    let entries = repository.provisional_entries().unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].path(), "docs/a,b.txt");
+    assert_eq!(entries[0].size(), Some(5));
+    assert_eq!(entries[0].digest("md5").unwrap().len(), 16);
+    assert_eq!(entries[1].path(), "photos/hello.txt");
+    assert_eq!(entries[1].size(), None);
+    assert_eq!(entries[1].digest("sha256").unwrap().len(), 32);
+}
+
+#[test]
+fn it_shreds_entry() {
+    let dir = test_dir("shred");
+    let repository = Repository::create(dir.to_str().unwrap(), "test", "payload").unwrap();
+    fs::create_dir_all(dir.join("objects")).unwrap();
+    fs::write(dir.join("objects/e1"), b"secret").unwrap();
+    insert_entry(&dir, "e1", b"secret", "objects/e1");
+
+    repository.shred("e1").unwrap();
+    assert!(!dir.join("objects/e1").exists());
+    let mut list = Vec::new();
+    assert_eq!(repository.export_checksums(&mut list, ChecksumFormat::Gnu, "blake3").unwrap(), 0);
+    let log = repository.audit_log().unwrap();
+    assert_eq!(log.len(), 1);
+    assert_eq!(log[0].action(), "shred");
+    assert_eq!(log[0].entry_id(), Some("e1"));
+    assert!(repository.shred("e1").is_err());
+
+    fs::write(dir.join("objects/shared"), b"twice").unwrap();
+    insert_entry(&dir, "e2", b"twice", "objects/shared");
+    insert_entry(&dir, "e3", b"twice", "objects/shared");
+    repository.shred("e2").unwrap();
+    assert!(repository.verify_entry("e3").unwrap().is_intact());
+    assert_eq!(repository.audit_log().unwrap()[1].detail(), Some("blob kept, shared with 1 other entries"));
+    repository.shred("e3").unwrap();
+    assert!(!dir.join("objects/shared").exists());
+}
+
+#[test]
+fn it_verifies_sample() {
+    let dir = test_dir("verify");
+    let repository = Repository::create(dir.to_str().unwrap(), "test", "payload").unwrap();
+    fs::create_dir_all(dir.join("objects")).unwrap();
+    for i in 0..10 {
+        let content = format!("content {}", i);
+        fs::write(dir.join(format!("objects/e{}", i)), &content).unwrap();
+        insert_entry(&dir, &format!("e{}", i), content.as_bytes(), &format!("objects/e{}", i));
+    }
+    fs::write(dir.join("objects/e3"), "damaged").unwrap();
+    fs::remove_file(dir.join("objects/e7")).unwrap();
+
+    let full = repository.verify(VerifyMode::Full).unwrap();
+    assert_eq!(full.checked(), 10);
+    assert_eq!(full.corrupted(), ["e3".to_string()]);
+    assert_eq!(full.missing(), ["e7".to_string()]);
+    assert_eq!(full.confidence_interval(), (0.2, 0.2));
+
+    let sample = repository.verify(VerifyMode::sample(0.5, 42)).unwrap();
+    assert_eq!(sample.total(), 10);
+    assert_eq!(sample.checked(), 5);
+    assert_eq!(sample, repository.verify(VerifyMode::sample(0.5, 42)).unwrap());
+    let (low, high) = sample.confidence_interval();
+    assert!(low <= sample.corruption_rate() && sample.corruption_rate() <= high);
+    assert!(repository.verify(VerifyMode::sample(0.0, 42)).is_err());
+
+    assert!(repository.verify_entry("e1").unwrap().is_intact());
+    let damaged = repository.verify_entry("e3").unwrap();
+    assert!(!damaged.is_intact() && !damaged.is_missing());
+    assert_eq!(damaged.actual(), Some(&blake3::hash(b"damaged").as_bytes()[..]));
+    assert!(repository.verify_entry("e7").unwrap().is_missing());
+    assert!(repository.verify_entry("unknown").is_err());
+}
+
+#[test]
+fn it_finds_entries_by_any_digest() {
+    let dir = test_dir("digest");
+    let repository = Repository::create(dir.to_str().unwrap(), "test", "payload").unwrap();
+    insert_entry(&dir, "e1", b"hello", "objects/e1");
+    let md5 = [0x5du8, 0x41, 0x40, 0x2a, 0xbc, 0x4b, 0x2a, 0x76, 0xb9, 0x71, 0x9d, 0x91, 0x10, 0x17, 0xc5, 0x92];
+    repository.record_digest("e1", "md5", &md5).unwrap();
+    repository.record_digest("e1", "md5", &md5).unwrap();
+    let err = repository.record_digest("e1", "md5", &[0; 4]).unwrap_err();
+    assert_eq!(err.custom_kind(), Some(&AppCustomErrorKind::InvalidInput));
+    let err = repository.record_digest("e1", "md5", &[0; 16]).unwrap_err();
+    assert_eq!(err.custom_kind(), Some(&AppCustomErrorKind::RepositoryMetadata));
+    assert!(repository.record_digest("e1", "blake3", &md5).is_err());
+    assert!(repository.record_digest("unknown", "md5", &md5).is_err());
+
+    let digests = repository.digests("e1").unwrap();
+    assert_eq!(digests.len(), 2);
+    assert_eq!(digests[0], ("blake3".to_string(), blake3::hash(b"hello").as_bytes().to_vec()));
+    assert_eq!(digests[1], ("md5".to_string(), md5.to_vec()));
+    assert_eq!(repository.find_by_digest("md5", &md5).unwrap(), ["e1"]);
+    assert_eq!(repository.find_by_digest("blake3", blake3::hash(b"hello").as_bytes()).unwrap(), ["e1"]);
+    assert!(repository.find_by_digest("sha256", &md5).unwrap().is_empty());
+}
+
+#[test]
+fn it_renders_and_parses_hash_formats() {
+    let hash = blake3::hash(b"hello");
+    let hex = HashFormat::Hex.encode("blake3", hash.as_bytes());
+    assert_eq!(hex, hash.to_hex().as_str());
+    assert_eq!(HashFormat::Hex.decode(&hex), Some((None, hash.as_bytes().to_vec())));
+    let base32 = HashFormat::Base32.encode("blake3", hash.as_bytes());
+    assert_eq!(base32.len(), 52);
+    assert_eq!(HashFormat::Base32.decode(&base32), Some((None, hash.as_bytes().to_vec())));
+
+    let multibase = HashFormat::Multibase.encode("blake3", hash.as_bytes());
+    assert!(multibase.starts_with("bdyq"));
+    assert_eq!(HashFormat::Multibase.decode(&multibase), Some((Some("blake3".to_string()), hash.as_bytes().to_vec())));
+    let md5 = HashFormat::Multibase.encode("md5", &[0u8; 16]);
+    assert_eq!(HashFormat::Multibase.decode(&md5), Some((Some("md5".to_string()), vec![0u8; 16])));
+    assert_eq!(HashFormat::Multibase.decode("f1e031234"), None);
+
+    let dir = test_dir("hash_format");
+    let repository = Repository::create(dir.to_str().unwrap(), "test", "payload").unwrap();
+    insert_entry(&dir, "e1", b"hello", "objects/e1");
+    assert_eq!(repository.find_by_multihash(&multibase).unwrap(), ["e1"]);
+    assert!(repository.find_by_multihash("not a multihash").is_err());
+}
+
+#[test]
+fn it_diffs_entry_content() {
+    let dir = test_dir("diff");
+    let repository = Repository::create(dir.to_str().unwrap(), "test", "payload").unwrap();
+    let text: String = (0..2000).map(|i| format!("line {} of the first revision\n", i)).collect();
+    let revised = text.replacen("line 1000 of", "an edited line 1000 of", 1);
+    let other: String = (0..2000).map(|i| format!("{} unrelated\n", i * 7919)).collect();
+    store_entry(&dir, "v1", text.as_bytes());
+    store_entry(&dir, "v2", revised.as_bytes());
+    store_entry(&dir, "copy", text.as_bytes());
+    store_entry(&dir, "other", other.as_bytes());
+
+    let same = repository.diff_entries("v1", "copy").unwrap();
+    assert!(same.identical() && same.size_delta() == 0 && same.similarity() == 1.0);
+    let revision = repository.diff_entries("v1", "v2").unwrap();
+    assert!(!revision.identical());
+    assert_eq!(revision.size_delta(), 10);
+    assert!(revision.similarity() > 0.9, "{}", revision.similarity());
+    assert!(repository.diff_entries("v1", "other").unwrap().similarity() < 0.1);
+    assert!(repository.diff_entries("v1", "unknown").is_err());
+
+    let unified = repository.unified_diff("v1", "v2", 1 << 20).unwrap().unwrap();
+    let context = |range: std::ops::Range<i32>| -> String {
+        range.map(|i| format!(" line {} of the first revision\n", i)).collect()
+    };
+    assert_eq!(unified, format!("--- v1\n+++ v2\n@@ -998,7 +998,7 @@\n{}-line 1000 of the first revision\n\
+                                 +an edited line 1000 of the first revision\n{}", context(997..1000), context(1001..1004)));
+    assert_eq!(repository.unified_diff("v1", "copy", 1 << 20).unwrap().unwrap(), "");
+    assert_eq!(repository.unified_diff("v1", "v2", 1000).unwrap(), None);
+    store_entry(&dir, "short", b"a\nb");
+    store_entry(&dir, "binary", &[0, 1, 2]);
+    assert_eq!(repository.unified_diff("short", "binary", 1000).unwrap(), None);
+    store_entry(&dir, "longer", b"a\nc\nd\n");
+    assert_eq!(repository.unified_diff("short", "longer", 1000).unwrap().unwrap(),
+               "--- short\n+++ longer\n@@ -1,2 +1,3 @@\n a\n-b\n\\ No newline at end of file\n+c\n+d\n");
+}
+
+#[test]
+fn it_previews_entries() {
+    let dir = test_dir("preview");
+    let repository = Repository::create(dir.to_str().unwrap(), "test", "payload").unwrap();
+    store_entry(&dir, "text", "héllo world".as_bytes());
+    store_entry(&dir, "binary", &[0x7f, b'E', b'L', b'F', 0, 1]);
+
+    let full = repository.preview("text", 100).unwrap();
+    assert!(full.is_text() && !full.truncated());
+    assert_eq!(full.text(), "héllo world");
+    assert!(!repository.preview("text", usize::MAX).unwrap().truncated());
+    let cut = repository.preview("text", 2).unwrap();
+    assert!(cut.is_text() && cut.truncated());
+    assert_eq!(cut.text(), "h");
+
+    let binary = repository.preview("binary", 4).unwrap();
+    assert!(!binary.is_text() && binary.truncated());
+    assert_eq!(binary.text(), format!("00000000  {:<47}  |.ELF|\n", "7f 45 4c 46"));
+}
+
+#[test]
+fn it_seals_storage_unit() {
+    let dir = test_dir("seal");
+    let repository = Repository::create(dir.to_str().unwrap(), "test", "payload").unwrap();
+    rusqlite::Connection::open(dir.join("afilia_repo.db")).unwrap()
+        .execute("INSERT INTO storage_unit (id, path) VALUES (1, 'objects')", []).unwrap();
+    store_entry(&dir, "e1", b"one");
+    store_entry(&dir, "e2", b"two");
+    insert_entry(&dir, "e3", b"elsewhere", "objects-2/e3");
+
+    let unit = repository.seal_storage_unit(1).unwrap();
+    assert!(unit.sealed().is_some());
+    assert_eq!(unit.file_count(), 2);
+    assert_eq!(unit.manifest_root().unwrap().len(), 32);
+    let manifest = fs::read_to_string(dir.join("objects").join(MANIFEST_FILE_NAME)).unwrap();
+    assert_eq!(manifest, format!("{}  e1\n{}  e2\n", blake3::hash(b"one").to_hex(), blake3::hash(b"two").to_hex()));
+    assert!(repository.seal_storage_unit(1).is_err());
+    assert!(repository.seal_storage_unit(2).is_err());
+
+    // the catalog refuses writes into the sealed unit, but not around it
+    let conn = rusqlite::Connection::open(dir.join("afilia_repo.db")).unwrap();
+    let refused = [
+        "INSERT INTO main_catalog (id, hash, storage_path) VALUES ('e4', x'00', 'objects/e4')",
+        "UPDATE main_catalog SET hash = x'00' WHERE id = 'e1'",
+        "UPDATE main_catalog SET storage_path = 'objects-2/e1' WHERE id = 'e1'",
+        "UPDATE main_catalog SET storage_path = 'objects/e3' WHERE id = 'e3'",
+    ];
+    for sql in refused {
+        let err = conn.execute(sql, []).unwrap_err();
+        assert!(err.to_string().contains("storage unit is sealed"), "{}: {}", sql, err);
+    }
+    conn.execute("INSERT INTO main_catalog (id, hash, storage_path) VALUES ('e5', x'00', 'objects-2/e5')", []).unwrap();
+    conn.execute("UPDATE main_catalog SET immutable = 1 WHERE id = 'e1'", []).unwrap();
+}
+
+#[test]
+fn it_creates_storage_units() {
+    let dir = test_dir("storage_root");
+    let repository = Repository::create_with_storage_root(dir.to_str().unwrap(), "test", "payload", DEFAULT_STORAGE_ROOT).unwrap();
+    let units = repository.storage_units().unwrap();
+    assert_eq!(units.len(), 1);
+    assert_eq!(units[0].path(), "objects");
+    assert!(dir.join("objects").is_dir());
+
+    assert!(repository.create_storage_unit("objects").is_err());
+    assert!(repository.create_storage_unit("objects/sub").is_err());
+    assert!(repository.create_storage_unit("../outside").is_err());
+    assert_eq!(repository.create_storage_unit("archive/2024").unwrap().path(), "archive/2024");
+    fs::write(dir.join("blocked"), b"file").unwrap();
+    let err = repository.create_storage_unit("blocked/unit").err().unwrap();
+    assert_eq!(err.exit_code(), 9);
+    assert_eq!(repository.storage_units().unwrap().len(), 2);
+
+    let blocked = test_dir("storage_root_blocked");
+    fs::write(blocked.join("objects"), b"file").unwrap();
+    assert!(Repository::create_with_storage_root(blocked.to_str().unwrap(), "test", "payload", DEFAULT_STORAGE_ROOT).is_err());
+    assert!(Repository::open(blocked.to_str().unwrap()).is_err());
+}
+
+#[test]
+fn it_verifies_storage_units_incrementally() {
+    let dir = test_dir("unit_verify");
+    let repository = Repository::create(dir.to_str().unwrap(), "test", "payload").unwrap();
+    let conn = rusqlite::Connection::open(dir.join("afilia_repo.db")).unwrap();
+    conn.execute("INSERT INTO storage_unit (id, path) VALUES (1, 'objects'), (2, 'other')", []).unwrap();
+    for id in ["e1", "e2", "e3"] {
+        store_entry(&dir, id, id.as_bytes());
+    }
+    fs::create_dir_all(dir.join("other")).unwrap();
+    fs::write(dir.join("other/e4"), b"four").unwrap();
+    insert_entry(&dir, "e4", b"four", "other/e4");
+    repository.seal_storage_unit(1).unwrap();
+    repository.seal_storage_unit(2).unwrap();
+    assert!(repository.verify_storage_units().unwrap().iter().all(|c| c.is_intact()));
+
+    repository.shred("e2").unwrap();
+    assert_eq!(repository.storage_unit(1).unwrap().file_count(), 2);
+    let reseal = repository.audit_log().unwrap().pop().unwrap();
+    assert_eq!(reseal.action(), "reseal");
+    assert!(reseal.detail().unwrap().starts_with("storage unit 1 root "));
+    assert!(repository.verify_storage_units().unwrap().iter().all(|c| c.is_intact()));
+    assert!(!fs::read_to_string(dir.join("objects").join(MANIFEST_FILE_NAME)).unwrap().contains("  e2\n"));
+
+    // damage the catalog behind the sealed unit triggers
+    conn.execute("DROP TRIGGER sealed_unit_update", []).unwrap();
+    conn.execute("UPDATE main_catalog SET hash = x'00' WHERE id = 'e3'", []).unwrap();
+    let checks = repository.verify_storage_units().unwrap();
+    assert_eq!((checks[0].unit_id(), checks[0].mismatched()), (1, &["e3".to_string()][..]));
+    assert!(checks[1].is_intact());
+
+    // blob rot behind an unchanged catalog is only seen by a full scan
+    fs::write(dir.join("other/e4"), b"rot!").unwrap();
+    assert!(repository.verify_storage_units().unwrap()[1].is_intact());
+    assert_eq!(repository.scan_storage_units().unwrap()[1].mismatched(), ["e4"]);
+    fs::remove_file(dir.join("other/e4")).unwrap();
+    assert_eq!(repository.scan_storage_units().unwrap()[1].mismatched(), ["e4"]);
+}
+
+#[test]
+fn it_exports_sealed_unit() {
+    let dir = test_dir("unit_export");
+    let dest = test_dir("unit_export_dest");
+    let repository = Repository::create(dir.to_str().unwrap(), "test", "payload").unwrap();
+    rusqlite::Connection::open(dir.join("afilia_repo.db")).unwrap()
+        .execute("INSERT INTO storage_unit (id, path) VALUES (1, 'objects')", []).unwrap();
+    store_entry(&dir, "e1", b"one");
+    store_entry(&dir, "e2", b"two");
+    store_entry(&dir, "e3", b"one");
+
+    assert!(repository.export_sealed_unit(1, &dest).is_err());
+    repository.seal_storage_unit(1).unwrap();
+    repository.export_sealed_unit(1, &dest).unwrap();
+    assert_eq!(fs::read_dir(dest.join("blobs")).unwrap().count(), 2);
+    let report = verify_unit_export(&dest).unwrap();
+    assert_eq!(report.checked(), 3);
+    assert!(report.corrupted().is_empty() && report.missing().is_empty());
+
+    fs::write(dest.join("blobs").join(blake3::hash(b"one").to_hex().as_str()), "damaged").unwrap();
+    assert_eq!(verify_unit_export(&dest).unwrap().corrupted(), ["e1", "e3"]);
+
+    let manifest = fs::read_to_string(dest.join(MANIFEST_FILE_NAME)).unwrap();
+    repository.export_sealed_unit(1, &dest).unwrap();
+    assert_eq!(fs::read_to_string(dest.join(MANIFEST_FILE_NAME)).unwrap(), manifest);
+    assert_eq!(manifest.lines().count(), 2);
+    let report = verify_unit_export(&dest).unwrap();
+    assert!(report.corrupted().is_empty() && report.missing().is_empty());
+}
+
+#[test]
+fn it_tails_change_feed() {
+    let dir = test_dir("changes");
+    let repository = Repository::create(dir.to_str().unwrap(), "test", "payload").unwrap();
+    store_entry(&dir, "e1", b"one");
+    repository.record_digest("e1", "md5", &[3; 16]).unwrap();
+    repository.shred("e1").unwrap();
+
+    let first = repository.changes(SequenceNumber::default(), 1).unwrap();
+    assert_eq!(first.len(), 1);
+    assert_eq!(first[0].kind(), ChangeKind::DigestRecorded);
+    let rest = repository.changes(first[0].seq(), 100).unwrap();
+    assert_eq!(rest.len(), 1);
+    assert_eq!(rest[0].kind(), ChangeKind::Shredded);
+    assert_eq!(rest[0].entry_id(), "e1");
+    assert!(rest[0].seq() > first[0].seq());
+    assert!(repository.changes(rest[0].seq(), 100).unwrap().is_empty());
+
+    // operations of a newer version are skipped without using up the limit
+    rusqlite::Connection::open(dir.join("afilia_repo.db")).unwrap()
+        .execute("INSERT INTO oplog (operation, entry_id, created) VALUES ('renamed', 'e1', '')", []).unwrap();
+    store_entry(&dir, "e2", b"two");
+    repository.shred("e2").unwrap();
+    let next = repository.changes(rest[0].seq(), 1).unwrap();
+    assert_eq!((next[0].kind(), next[0].entry_id()), (ChangeKind::Shredded, "e2"));
+    assert!(repository.changes(SequenceNumber::from(u64::MAX), 1).is_err());
+}
+
+#[test]
+fn it_subscribes_to_changes() {
+    let dir = test_dir("subscribe");
+    let path = dir.to_str().unwrap();
+    let repository = Repository::create(path, "test", "payload").unwrap();
+    store_entry(&dir, "e1", b"one");
+    let subscription = repository.subscribe(SequenceNumber::default(), Duration::from_millis(10)).unwrap();
+    assert!(subscription.next_timeout(Duration::from_millis(50)).is_none());
+
+    // A change committed through another handle, as by another process
+    Repository::open(path).unwrap().record_digest("e1", "md5", &[3; 16]).unwrap();
+    let change = subscription.next_timeout(Duration::from_secs(5)).unwrap().unwrap();
+    assert_eq!(change.kind(), ChangeKind::DigestRecorded);
+    assert_eq!(change.entry_id(), "e1");
+
+    let idle = repository.subscribe(change.seq(), Duration::from_secs(3600)).unwrap();
+    assert!(idle.next_timeout(Duration::from_millis(50)).is_none());
+    let dropped = Instant::now();
+    drop(idle);
+    assert!(dropped.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn it_protects_immutable_entries() {
+    let dir = test_dir("immutable");
+    let repository = Repository::create(dir.to_str().unwrap(), "test", "payload").unwrap();
+    store_entry(&dir, "e1", b"golden master");
+    repository.record_digest("e1", "md5", &[1; 16]).unwrap();
+    repository.set_immutable("e1", true).unwrap();
+    assert!(repository.is_immutable("e1").unwrap());
+
+    let err = repository.shred("e1").unwrap_err();
+    assert_eq!(err.custom_kind(), Some(&AppCustomErrorKind::ImmutableEntry));
+    assert!(dir.join("objects/e1").exists());
+    assert!(repository.record_digest("e1", "md5", &[2; 16]).is_err());
+    repository.record_digest("e1", "sha1", &[3; 20]).unwrap();
+
+    repository.set_immutable("e1", false).unwrap();
+    repository.shred("e1").unwrap();
+    let actions: Vec<String> = repository.audit_log().unwrap().iter().map(|r| r.action().to_string()).collect();
+    assert_eq!(actions, ["set_immutable", "clear_immutable", "shred"]);
+    assert!(repository.is_immutable("unknown").is_err());
+}
+
+#[test]
+fn it_keeps_annotations_editable() {
+    let dir = test_dir("annotation");
+    let repository = Repository::create(dir.to_str().unwrap(), "test", "payload").unwrap();
+    store_entry(&dir, "e1", b"photo");
+    repository.set_immutable("e1", true).unwrap();
+
+    repository.annotate("e1", "place", Some("Nice")).unwrap();
+    repository.annotate("e1", "note", Some("beach")).unwrap();
+    repository.annotate("e1", "place", Some("Nice")).unwrap();
+    let err = repository.annotate("e1", "place", Some("Cannes")).unwrap_err();
+    assert_eq!(err.custom_kind(), Some(&AppCustomErrorKind::ImmutableEntry));
+    assert!(repository.annotate("e1", "note", None).is_err());
+    assert_eq!(repository.annotations("e1").unwrap(),
+               [("note".to_string(), "beach".to_string()), ("place".to_string(), "Nice".to_string())]);
+
+    repository.set_immutable("e1", false).unwrap();
+    repository.annotate("e1", "place", Some("Cannes")).unwrap();
+    repository.annotate("e1", "note", None).unwrap();
+    assert_eq!(repository.annotations("e1").unwrap(), [("place".to_string(), "Cannes".to_string())]);
+    assert!(repository.annotate("unknown", "note", Some("x")).is_err());
+    repository.annotate("e1", "note", None).unwrap();
+    let changes = repository.changes(SequenceNumber::default(), 10).unwrap();
+    assert_eq!(changes.last().unwrap().kind(), ChangeKind::AnnotationChanged);
+    // unchanged values and removals of missing annotations are not changes
+    assert_eq!(changes.iter().filter(|c| c.kind() == ChangeKind::AnnotationChanged).count(), 4);
+
+    repository.shred("e1").unwrap();
+    assert!(repository.annotations("e1").unwrap().is_empty());
+}
+
+#[test]
+fn it_applies_metadata_files() {
+    let dir = test_dir("metadata_file");
+    let repository = Repository::create(dir.to_str().unwrap(), "test", "payload").unwrap();
+    store_entry(&dir, "e1", b"one");
+    store_entry(&dir, "e2", b"two");
+    let csv = format!("hash,path,title,note\r\n{},,\"Beach, 1998\",\n,objects/e2,\"Say \"\"hi\"\"\",kept\n,objects/none,x,y\n",
+                      blake3::hash(b"one").to_hex());
+
+    let dry = repository.apply_metadata_file(csv.as_bytes(), MappingFormat::Csv, true).unwrap();
+    assert_eq!((dry.rows(), dry.annotated(), dry.unmatched()), (3, 3, &[3][..]));
+    assert!(repository.annotations("e1").unwrap().is_empty());
+    repository.apply_metadata_file(csv.as_bytes(), MappingFormat::Csv, false).unwrap();
+    assert_eq!(repository.annotations("e1").unwrap(), [("title".to_string(), "Beach, 1998".to_string())]);
+    assert_eq!(repository.annotations("e2").unwrap().len(), 2);
+
+    let json = r#"[{"path": "objects/e1", "title": "Beach"}]"#;
+    assert_eq!(repository.apply_metadata_file(json.as_bytes(), MappingFormat::Json, false).unwrap().annotated(), 1);
+    assert_eq!(repository.annotations("e1").unwrap()[0].1, "Beach");
+    let err = repository.apply_metadata_file("title\nx\n".as_bytes(), MappingFormat::Csv, false).unwrap_err();
+    assert_eq!(err.custom_kind(), Some(&AppCustomErrorKind::MetadataFile));
+    assert!(repository.apply_metadata_file("path,title\n\"open".as_bytes(), MappingFormat::Csv, false).is_err());
+
+    let triage = "\u{feff}path,rating,review_state\nobjects/e1,4,kept\nobjects/e2,9,\nobjects/e2,,maybe\n";
+    let err = repository.apply_metadata_file(triage.as_bytes(), MappingFormat::Csv, true).err().unwrap();
+    assert_eq!(err.msg(), "invalid rating or review state in rows 2, 3");
+    let triage = "\u{feff}path,rating,review_state\nobjects/e1,4,kept\n";
+    repository.apply_metadata_file(triage.as_bytes(), MappingFormat::Csv, false).unwrap();
+    assert_eq!((repository.rating("e1").unwrap(), repository.review_state("e1").unwrap()), (Some(4), ReviewState::Kept));
+
+    for (content, format) in [("path,title,title\nobjects/e1,a,b\n", MappingFormat::Csv),
+                              ("path,,title\nobjects/e1,a,b\n", MappingFormat::Csv),
+                              (r#"[{"path": "objects/e1", "title": "a", "title": "b"}]"#, MappingFormat::Json),
+                              (r#"[{"path": "objects/e1", "": "a"}]"#, MappingFormat::Json)] {
+        let err = repository.apply_metadata_file(content.as_bytes(), format, true).unwrap_err();
+        assert_eq!(err.custom_kind(), Some(&AppCustomErrorKind::MetadataFile), "{}", content);
+    }
+
+    // a dry run fails where the real run would
+    repository.set_immutable("e1", true).unwrap();
+    let retitle = "path,title\nobjects/e1,Cannes\n";
+    let err = repository.apply_metadata_file(retitle.as_bytes(), MappingFormat::Csv, true).unwrap_err();
+    assert_eq!(err.exit_code(), 7);
+    repository.set_immutable("e1", false).unwrap();
+    repository.freeze().unwrap();
+    let err = repository.apply_metadata_file(retitle.as_bytes(), MappingFormat::Csv, true).unwrap_err();
+    assert_eq!(err.custom_kind(), Some(&AppCustomErrorKind::FrozenRepository));
+}
+
+#[test]
+fn it_triages_entries() {
+    let dir = test_dir("triage");
+    let repository = Repository::create(dir.to_str().unwrap(), "test", "payload").unwrap();
+    for id in ["e1", "e2", "e3"] {
+        store_entry(&dir, id, id.as_bytes());
+    }
+    repository.set_rating(&["e1", "e2"], Some(4)).unwrap();
+    repository.set_rating(&["e2"], Some(2)).unwrap();
+    assert_eq!(repository.rating("e1").unwrap(), Some(4));
+    assert_eq!(repository.rating("e3").unwrap(), None);
+    assert_eq!(repository.entries_rated_at_least(3).unwrap(), ["e1"]);
+    assert!(repository.set_rating(&["e1"], Some(6)).is_err());
+    assert!(repository.annotate("e1", "rating", Some("banana")).is_err());
+    assert!(repository.annotate("e1", "review_state", None).is_err());
+
+    repository.set_review_state(&["e1", "e3"], ReviewState::Kept).unwrap();
+    repository.set_review_state(&["e3"], ReviewState::FlaggedForDeletion).unwrap();
+    assert!(repository.set_review_state(&["e2", "unknown"], ReviewState::Kept).is_err());
+    assert_eq!(repository.review_state("e2").unwrap(), ReviewState::Unreviewed);
+    assert_eq!(repository.entries_in_review_state(ReviewState::Kept).unwrap(), ["e1"]);
+    assert_eq!(repository.entries_in_review_state(ReviewState::Unreviewed).unwrap(), ["e2"]);
+    assert_eq!(repository.entries_in_review_state(ReviewState::FlaggedForDeletion).unwrap(), ["e3"]);
+}
+
+#[test]
+fn it_traverses_entry_relations() {
+    let dir = test_dir("relation");
+    let repository = Repository::create(dir.to_str().unwrap(), "test", "payload").unwrap();
+    for id in ["v1", "v2", "v3", "mail", "a1", "a2"] {
+        store_entry(&dir, id, id.as_bytes());
+    }
+    repository.relate("v2", RelationKind::Supersedes, "v1").unwrap();
+    repository.relate("v3", RelationKind::Supersedes, "v2").unwrap();
+    repository.relate("a2", RelationKind::AttachmentOf, "mail").unwrap();
+    repository.relate("a1", RelationKind::AttachmentOf, "mail").unwrap();
+    repository.relate("a1", RelationKind::AttachmentOf, "mail").unwrap();
+    assert!(repository.relate("v1", RelationKind::Supersedes, "v1").is_err());
+    assert!(repository.relate("v1", RelationKind::Supersedes, "unknown").is_err());
+
+    assert_eq!(repository.traverse("v3", RelationKind::Supersedes, Direction::Outgoing).unwrap(), ["v2", "v1"]);
+    assert_eq!(repository.traverse("v1", RelationKind::Supersedes, Direction::Incoming).unwrap(), ["v2", "v3"]);
+    assert_eq!(repository.related("mail", RelationKind::AttachmentOf, Direction::Incoming).unwrap(), ["a1", "a2"]);
+    assert!(repository.related("mail", RelationKind::PartOf, Direction::Incoming).unwrap().is_empty());
+
+    repository.relate("v1", RelationKind::Supersedes, "v3").unwrap();
+    assert_eq!(repository.traverse("v1", RelationKind::Supersedes, Direction::Outgoing).unwrap(), ["v3", "v2"]);
+    repository.unrelate("v1", RelationKind::Supersedes, "v3").unwrap();
+    repository.set_immutable("mail", true).unwrap();
+    let err = repository.shred("a1").unwrap_err();
+    assert_eq!((err.exit_code(), err.to_string().contains("immutable entry mail")), (7, true));
+    repository.set_immutable("mail", false).unwrap();
+    repository.shred("a1").unwrap();
+    assert_eq!(repository.related("mail", RelationKind::AttachmentOf, Direction::Incoming).unwrap(), ["a2"]);
+    let relation_changes = repository.changes(SequenceNumber::default(), 100).unwrap().iter()
+        .filter(|c| c.kind() == ChangeKind::RelationChanged).count();
+    assert_eq!(relation_changes, 6);
+
+    let parts: Vec<String> = (0..8).map(|i| format!("p{}", i)).collect();
+    for part in &parts {
+        store_entry(&dir, part, part.as_bytes());
+    }
+    for source in &parts {
+        for target in parts.iter().filter(|target| *target != source) {
+            repository.relate(source, RelationKind::PartOf, target).unwrap();
+        }
+    }
+    assert_eq!(repository.traverse("p0", RelationKind::PartOf, Direction::Outgoing).unwrap(), parts[1..]);
+}
+
+#[test]
+fn it_registers_extension_schemas() {
+    let dir = test_dir("extension");
+    let repository = Repository::create(dir.to_str().unwrap(), "test", "payload").unwrap();
+    let migrations = ["CREATE TABLE ext_app_item (id INTEGER PRIMARY KEY, label VARCHAR)",
+                      "ALTER TABLE ext_app_item ADD COLUMN score INTEGER;
+                       CREATE INDEX ext_app_item_score ON ext_app_item (score)"];
+    assert_eq!(repository.register_extension_schema("app", &migrations[..1]).unwrap(), 1);
+    assert_eq!(repository.register_extension_schema("app", &migrations).unwrap(), 1);
+    assert_eq!(repository.register_extension_schema("app", &migrations).unwrap(), 0);
+    assert_eq!(repository.extension_schema_version("app").unwrap(), 2);
+    assert!(repository.register_extension_schema("app", &migrations[..1]).is_err());
+
+    let err = repository.register_extension_schema("rogue",
+        &["CREATE TABLE ext_rogue_ok (id INTEGER)", "ALTER TABLE main_catalog ADD COLUMN rogue INTEGER"]).unwrap_err();
+    assert_eq!(err.custom_kind(), Some(&AppCustomErrorKind::ExtensionSchema));
+    assert_eq!(repository.extension_schema_version("rogue").unwrap(), 0);
+    assert!(repository.register_extension_schema("Bad-Name", &[]).is_err());
+    store_entry(&dir, "e1", b"one");
+    for migration in ["DELETE FROM main_catalog", "UPDATE parameter SET value = 'x'", "DELETE FROM audit_log",
+                      "CREATE TABLE ext_rogue_t (id INTEGER); CREATE TRIGGER ext_rogue_tr AFTER DELETE ON main_catalog BEGIN SELECT 1; END",
+                      "CREATE TEMP TRIGGER ext_rogue_tt AFTER INSERT ON main.main_catalog BEGIN SELECT 1; END",
+                      "CREATE TEMP TABLE ext_rogue_tmp (id INTEGER)",
+                      "UPDATE sqlite_sequence SET seq = 0 WHERE name = 'oplog'",
+                      "DROP INDEX entry_digest_hash",
+                      "DROP TABLE relation"] {
+        let err = repository.register_extension_schema("rogue", &[migration]).unwrap_err();
+        assert_eq!(err.custom_kind(), Some(&AppCustomErrorKind::ExtensionSchema), "{}", migration);
+    }
+    assert!(repository.verify_entry("e1").unwrap().is_intact());
+    assert_eq!(repository.register_extension_schema("seq", &[
+        "CREATE TABLE ext_seq_item (id INTEGER PRIMARY KEY AUTOINCREMENT); INSERT INTO ext_seq_item DEFAULT VALUES",
+        "CREATE TABLE ext_seq_tmp (id INTEGER); CREATE INDEX ext_seq_idx ON ext_seq_tmp (id);
+         DROP INDEX ext_seq_idx; DROP TABLE ext_seq_tmp"]).unwrap(), 2);
+    assert!(repository.register_extension_schema("seq", &["", "", "DROP TABLE ext_seq_item"]).is_err());
+    let reopened = Repository::open(dir.to_str().unwrap()).unwrap();
+    assert_eq!(reopened.register_extension_schema("rogue", &["CREATE TABLE ext_rogue_ok (id INTEGER)"]).unwrap(), 1);
+}
+
+#[test]
+fn it_maps_errors_to_stable_exit_codes() {
+    let dir = test_dir("exit_code");
+    assert_eq!(Repository::open(dir.to_str().unwrap()).err().unwrap().exit_code(), 3);
+    let repository = Repository::create(dir.to_str().unwrap(), "test", "payload").unwrap();
+    let lease = repository.acquire_lease(Duration::from_secs(60)).unwrap();
+    assert_eq!(repository.acquire_lease(Duration::from_secs(60)).unwrap_err().exit_code(), 5);
+    repository.release_lease(lease).unwrap();
+    store_entry(&dir, "e1", b"content");
+    repository.set_immutable("e1", true).unwrap();
+    assert_eq!(repository.shred("e1").unwrap_err().exit_code(), 7);
+    assert_eq!(repository.shred("unknown").unwrap_err().exit_code(), 10);
+    assert_eq!(repository.import_checksums("garbage".as_bytes(), "sha256").unwrap_err().exit_code(), 8);
+    let err = repository.verify(VerifyMode::sample(1.5, 0)).unwrap_err();
+    assert_eq!((err.custom_kind(), err.exit_code()), (Some(&AppCustomErrorKind::InvalidInput), 8));
+    assert_eq!(repository.storage_unit(99).unwrap_err().exit_code(), 10);
+}
+
+#[test]
+fn it_reads_from_pinned_snapshot() {
+    let dir = test_dir("snapshot");
+    let repository = Repository::create(dir.to_str().unwrap(), "test", "payload").unwrap();
+    store_entry(&dir, "e1", b"one");
+    let snapshot = repository.snapshot().unwrap();
+    store_entry(&dir, "e2", b"two");
+    repository.record_digest("e1", "md5", &[1; 16]).unwrap();
+
+    assert_eq!(snapshot.digests("e1").unwrap().len(), 1);
+    assert!(snapshot.is_immutable("e2").is_err());
+    assert_eq!(snapshot.uuid(), repository.uuid());
+    assert!(snapshot.set_immutable("e1", true).is_err());
+    assert!(snapshot.acquire_lease(Duration::from_secs(60)).is_err());
+    repository.release_lease(repository.acquire_lease(Duration::from_secs(60)).unwrap()).unwrap();
+    assert_eq!(repository.digests("e1").unwrap().len(), 2);
+    assert!(!repository.is_immutable("e2").unwrap());
+    drop(snapshot);
+    assert!(!repository.is_immutable("e1").unwrap());
+}
+
+#[test]
+fn it_manages_failed_queue_items() {
+    let dir = test_dir("queue");
+    let repository = Repository::create(dir.to_str().unwrap(), "test", "payload").unwrap();
+    let conn = rusqlite::Connection::open(dir.join("afilia_repo.db")).unwrap();
+    for (id, status, attempts, error) in [("q1", "pending", 0, None), ("q2", "failed", 1, Some("disk full")),
+                                          ("q3", "dead_letter", 5, Some("unreadable")), ("q4", "dead_letter", 5, None)] {
+        conn.execute("INSERT INTO queue (id, hash, status, attempts, error) VALUES (?1, x'00', ?2, ?3, ?4)",
+                     rusqlite::params![id, status, attempts, error]).unwrap();
+    }
+    assert_eq!(repository.queue_items(None).unwrap().len(), 4);
+    let failed = repository.queue_items(Some(QueueStatus::Failed)).unwrap();
+    assert_eq!((failed[0].id(), failed[0].attempts(), failed[0].error()), ("q2", 1, Some("disk full")));
+
+    repository.requeue("q2").unwrap();
+    let requeued = &repository.queue_items(Some(QueueStatus::Pending)).unwrap()[1];
+    assert_eq!((requeued.id(), requeued.attempts(), requeued.error()), ("q2", 1, None));
+    repository.discard("q1").unwrap();
+    assert!(repository.requeue("q1").is_err());
+    assert_eq!(repository.requeue_all(QueueStatus::Failed).unwrap(), 0);
+    assert_eq!(repository.discard_all(QueueStatus::DeadLetter).unwrap(), 2);
+    assert_eq!(repository.queue_items(None).unwrap().len(), 1);
+    assert_eq!(repository.audit_log().unwrap().len(), 3);
+}
+
+#[test]
+fn it_checks_repository_against_passport() {
+    let dir = test_dir("passport");
+    let repository = Repository::create(dir.to_str().unwrap(), "drive 7", "payload").unwrap();
+    store_entry(&dir, "e1", b"one");
+    store_entry(&dir, "e2", b"two");
+    let json = repository.passport().unwrap().to_json().unwrap();
+    let passport = Passport::from_json(&json).unwrap();
+    assert_eq!((passport.name(), passport.entries(), passport.size()), ("drive 7", 2, 6));
+    assert!(repository.check_passport(&passport).unwrap().is_empty());
+
+    let forged = Passport::from_json(&json.replace("\"entries\": 2", "\"entries\": 3")).unwrap();
+    assert_eq!(repository.check_passport(&forged).unwrap_err().custom_kind(), Some(&AppCustomErrorKind::RepositorySign));
+    let other_dir = test_dir("passport_other");
+    let other = Repository::create(other_dir.to_str().unwrap(), "drive 7", "payload").unwrap();
+    assert!(other.check_passport(&passport).is_err());
+
+    let mut multibase = Repository::open(dir.to_str().unwrap()).unwrap();
+    multibase.set_hash_format(HashFormat::Multibase);
+    let rendered = multibase.passport().unwrap();
+    assert!(rendered.catalog_root().starts_with("bdyq"));
+    assert!(repository.check_passport(&rendered).unwrap().is_empty());
+
+    store_entry(&dir, "e3", b"three");
+    assert_eq!(repository.check_passport(&passport).unwrap(), ["entries", "size", "catalog_root"]);
+}
+
+#[test]
+fn it_freezes_and_thaws_repository() {
+    let dir = test_dir("freeze");
+    let repository = Repository::create(dir.to_str().unwrap(), "test", "payload").unwrap();
+    rusqlite::Connection::open(dir.join("afilia_repo.db")).unwrap()
+        .execute("INSERT INTO storage_unit (id, path) VALUES (1, 'objects')", []).unwrap();
+    store_entry(&dir, "e1", b"one");
+    store_entry(&dir, "e2", b"two");
+
+    let certificate = repository.freeze().unwrap();
+    assert!(repository.is_frozen().unwrap());
+    assert!(repository.storage_unit(1).unwrap().sealed().is_some());
+    assert_eq!(repository.freeze_certificate().unwrap(), Some(certificate.clone()));
+    assert!(repository.check_passport(&certificate).unwrap().is_empty());
+    assert!(fs::metadata(dir.join("objects/e1")).unwrap().permissions().readonly());
+    let err = repository.annotate("e1", "title", Some("x")).err().unwrap();
+    assert_eq!(err.custom_kind(), Some(&AppCustomErrorKind::FrozenRepository));
+    assert_eq!(err.exit_code(), 7);
+    assert!(repository.shred("e2").is_err());
+    assert!(repository.freeze().is_err());
+
+    repository.thaw().unwrap();
+    assert!(!repository.is_frozen().unwrap());
+    assert!(!fs::metadata(dir.join("objects/e1")).unwrap().permissions().readonly());
+    repository.annotate("e1", "title", Some("x")).unwrap();
+    assert!(repository.thaw().is_err());
+    let actions: Vec<String> = repository.audit_log().unwrap().iter().map(|r| r.action().to_string()).collect();
+    assert!(actions.ends_with(&["freeze".to_string(), "thaw".to_string()]));
+
+    rusqlite::Connection::open(dir.join("afilia_repo.db")).unwrap()
+        .execute("INSERT INTO storage_unit (id, path) VALUES (2, 'objects-2')", []).unwrap();
+    fs::write(dir.join("objects/e2"), b"rot").unwrap();
+    assert_eq!(repository.freeze().err().unwrap().custom_kind(), Some(&AppCustomErrorKind::Verify));
+    assert!(!repository.is_frozen().unwrap());
+    assert!(repository.storage_unit(2).unwrap().sealed().is_none());
+
+    // permissions already changed are restored when freezing fails half way
+    fs::write(dir.join("objects/e2"), b"two").unwrap();
+    fs::create_dir_all(dir.join("objects-2")).unwrap();
+    fs::remove_file(dir.join("objects").join(MANIFEST_FILE_NAME)).unwrap();
+    let err = repository.freeze().unwrap_err();
+    assert!(err.msg().contains(MANIFEST_FILE_NAME), "{}", err);
+    assert!(!repository.is_frozen().unwrap());
+    assert!(!fs::metadata(dir.join("objects/e1")).unwrap().permissions().readonly());
+}
+
+#[cfg(unix)]
+#[test]
+fn it_restores_permissions_after_thaw() {
+    use std::os::unix::fs::PermissionsExt;
+    let dir = test_dir("freeze_mode");
+    let repository = Repository::create(dir.to_str().unwrap(), "test", "payload").unwrap();
+    store_entry(&dir, "e1", b"one");
+    let blob = dir.join("objects/e1");
+    fs::set_permissions(&blob, fs::Permissions::from_mode(0o664)).unwrap();
+    repository.freeze().unwrap();
+    assert_eq!(fs::metadata(&blob).unwrap().permissions().mode() & 0o777, 0o464);
+    repository.thaw().unwrap();
+    assert_eq!(fs::metadata(&blob).unwrap().permissions().mode() & 0o777, 0o664);
+}
+
+#[test]
+fn it_exports_recovery_kit() {
+    let dir = test_dir("recovery_kit");
+    let repository = Repository::create(dir.to_str().unwrap(), "test", "payload").unwrap();
+    rusqlite::Connection::open(dir.join("afilia_repo.db")).unwrap()
+        .execute("INSERT INTO storage_unit (id, path) VALUES (1, 'objects')", []).unwrap();
+    store_entry(&dir, "e1", b"one");
+    let unit = repository.seal_storage_unit(1).unwrap();
+    let kit = dir.join("kit");
+
+    repository.export_recovery_kit(&kit).unwrap();
+    insert_entry(&dir, "e2", b"two", "objects-2/e2");
+    repository.export_recovery_kit(&kit).unwrap();
+    assert_eq!(fs::read(kit.join(".afilia_repo")).unwrap(), fs::read(dir.join(".afilia_repo")).unwrap());
+    let catalog_copy = rusqlite::Connection::open(kit.join("afilia_repo.db")).unwrap();
+    let entries: i64 = catalog_copy.query_row("SELECT count(*) FROM main_catalog", [], |row| row.get(0)).unwrap();
+    assert_eq!(entries, 2);
+
+    let index: serde_json::Value = serde_json::from_str(&fs::read_to_string(kit.join(RECOVERY_INDEX_FILE_NAME)).unwrap()).unwrap();
+    assert_eq!(index["parameters"]["format_version"], "1.0");
+    let root: String = unit.manifest_root().unwrap().iter().map(|b| format!("{:02x}", b)).collect();
+    assert_eq!(index["storage_units"][0]["manifest_root"], root.as_str());
+    let passport = Passport::from_json(&index["passport"].to_string()).unwrap();
+    assert!(repository.check_passport(&passport).unwrap().is_empty());
+}
+
+#[test]
+fn it_takes_time_from_repository_clock() {
+    let dir = test_dir("clock");
+    let path = dir.to_str().unwrap();
+    let clock = Arc::new(TestClock::at_unix(1_700_000_000));
+    let mut daemon = Repository::create(path, "test", "payload").unwrap();
+    let mut cli = Repository::open(path).unwrap();
+    daemon.set_clock(clock.clone());
+    cli.set_clock(clock.clone());
+
+    let lease = daemon.acquire_lease(Duration::from_secs(60)).unwrap();
+    assert_eq!(lease.expires(), 1_700_000_060);
+    clock.advance(Duration::from_secs(59));
+    assert!(cli.acquire_lease(Duration::from_secs(60)).is_err());
+    clock.advance(Duration::from_secs(1));
+    cli.acquire_lease(Duration::from_secs(60)).unwrap();
+
+    store_entry(&dir, "e1", b"one");
+    daemon.set_immutable("e1", true).unwrap();
+    assert_eq!(daemon.audit_log().unwrap()[0].created(), "2023-11-14 22:14:20");
+
+    clock.set(UNIX_EPOCH - Duration::from_secs(1));
+    let err = daemon.renew_lease(&lease, Duration::from_secs(60)).unwrap_err();
+    assert!(matches!(err.error_kind(), InternalError::SystemTime(_)));
+}
+
+#[derive(Default)]
+struct EventLog(Mutex<Vec<String>>);
+
+impl TelemetrySink for EventLog {
+    fn event(&self, name: &str, fields: &[(&str, &str)]) {
+        let fields: Vec<String> = fields.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+        self.0.lock().unwrap().push(format!("{} {}", name, fields.join(" ")));
+    }
+}
+
+#[test]
+fn it_reports_telemetry() {
+    let dir = test_dir("telemetry");
+    let mut repository = Repository::create(dir.to_str().unwrap(), "test", "payload").unwrap();
+    store_entry(&dir, "e1", b"one");
+    store_entry(&dir, "e2", b"two");
+    let metrics = Arc::new(PrometheusTelemetry::new());
+    repository.set_telemetry(metrics.clone());
+    repository.verify(VerifyMode::Full).unwrap();
+    repository.shred("e2").unwrap();
+    repository.verify(VerifyMode::Full).unwrap();
+    assert_eq!(metrics.render(), "# TYPE afilia_entries_corrupted_total counter\nafilia_entries_corrupted_total 0\n\
+        # TYPE afilia_entries_missing_total counter\nafilia_entries_missing_total 0\n\
+        # TYPE afilia_entries_shredded_total counter\nafilia_entries_shredded_total 1\n\
+        # TYPE afilia_entries_verified_total counter\nafilia_entries_verified_total 3\n");
+
+    let events = Arc::new(EventLog::default());
+    repository.set_telemetry(events.clone());
+    repository.verify(VerifyMode::Full).unwrap();
+    assert_eq!(*events.0.lock().unwrap(), ["verify checked=1 corrupted=0 missing=0"]);
+    let passport = repository.freeze().unwrap();
+    repository.thaw().unwrap();
+    assert_eq!(events.0.lock().unwrap()[2..],
+               [format!("freeze catalog_root={}", passport.catalog_root()), "thaw ".to_string()]);
+
+    let saturated = PrometheusTelemetry::new();
+    saturated.counter("afilia_test_total", u64::MAX);
+    saturated.counter("afilia_test_total", 1);
+    assert!(saturated.render().ends_with(&format!("afilia_test_total {}\n", u64::MAX)));
+}
+
+#[test]
+fn it_runs_environment_checks() {
+    let dir = test_dir("doctor");
+    let mut repository = Repository::create(dir.to_str().unwrap(), "test", "payload").unwrap();
+    let report = repository.doctor().unwrap();
+    assert!(report.is_healthy(), "{:?}", report);
+    let names: Vec<&str> = report.checks().iter().map(|c| c.name()).collect();
+    assert_eq!(names, ["sqlite_version", "journal_mode", "permissions", "hardlinks", "clock", "writer_lease",
+                       "free_space", "reflinks", "xattrs"]);
+    assert_eq!(report.checks()[6].status(), CheckStatus::Unsupported);
+    assert!(!dir.join(".afilia_doctor").exists());
+
+    let _lease = repository.acquire_lease(Duration::from_secs(60)).unwrap();
+    repository.set_clock(Arc::new(TestClock::at_unix(0)));
+    let report = repository.doctor().unwrap();
+    assert!(!report.is_healthy());
+    let status: Vec<CheckStatus> = report.checks().iter().map(|c| c.status()).collect();
+    assert_eq!(status[4..6], [CheckStatus::Fail, CheckStatus::Warn]);
+}
+
+#[test]
+fn it_copies_files_safely() {
+    let dir = test_dir("safe_copy");
+    let src = dir.join("src");
+    fs::write(&src, b"content").unwrap();
+    let expected = blake3::hash(b"content");
+
+    let dst = dir.join("dst");
+    assert_eq!(safe_copy(&src, &dst, Some(&expected)).unwrap(), expected);
+    assert_eq!(fs::read(&dst).unwrap(), b"content");
+
+    let other = dir.join("other");
+    let err = safe_copy(&src, &other, Some(&blake3::hash(b"else"))).unwrap_err();
+    assert_eq!(err.custom_kind(), Some(&AppCustomErrorKind::SafeCopy));
+    assert!(!other.exists());
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+    assert!(safe_copy(&dir.join("missing"), &other, None).is_err());
+}
@@ -0,0 +1,54 @@
+#![cfg(feature = "testkit")]
+mod common;
+
+use std::fs;
+use std::sync::Arc;
+use afilia::filesystem::clock::TestClock;
+use afilia::filesystem::repository::Repository;
+use afilia::filesystem::verify::VerifyMode;
+use afilia::testkit;
+use common::{store_entry, test_dir};
+use uuid::Uuid;
+
+#[test]
+fn it_detects_injected_corruption() {
+    let dir = test_dir("testkit");
+    let path = dir.to_str().unwrap();
+    let repository = Repository::create(path, "test", "payload").unwrap();
+    for id in ["e1", "e2", "e3", "e4"] {
+        store_entry(&dir, id, format!("content of {}", id).as_bytes());
+    }
+    testkit::corrupt_blob(&repository, "e1", 3).unwrap();
+    testkit::truncate_blob(&repository, "e2", 2).unwrap();
+    rusqlite::Connection::open(dir.join("afilia_repo.db")).unwrap()
+        .execute("INSERT INTO storage_unit (id, path) VALUES (1, 'objects')", []).unwrap();
+    repository.seal_storage_unit(1).unwrap();
+    testkit::flip_catalog_row(&repository, "e3").unwrap();
+
+    let report = repository.verify(VerifyMode::Full).unwrap();
+    assert_eq!(report.corrupted(), ["e1", "e2", "e3"]);
+    assert!(testkit::corrupt_blob(&repository, "e4", 1000).is_err());
+
+    testkit::damage_sign_file(&repository).unwrap();
+    assert!(Repository::open(path).is_err());
+}
+
+#[test]
+fn it_creates_byte_identical_repositories() {
+    let uuid = Uuid::parse_str("6f9619ff-8b86-d011-b42d-00cf4fc964ff").unwrap();
+    let dirs = [test_dir("deterministic_a"), test_dir("deterministic_b")];
+    for dir in dirs.iter() {
+        let clock = Arc::new(TestClock::at_unix(1_700_000_000));
+        let repository = testkit::create_deterministic(dir.to_str().unwrap(), "test", "payload", uuid, clock, 7).unwrap();
+        let list = format!("SHA256 (a.txt) = {}\nSHA256 (b.txt) = {}\n", "00ff".repeat(16), "ff00".repeat(16));
+        repository.import_checksums(list.as_bytes(), "sha256").unwrap();
+        assert_eq!(repository.uuid(), &uuid);
+    }
+    for file in [".afilia_repo", "afilia_repo.db"] {
+        assert_eq!(fs::read(dirs[0].join(file)).unwrap(), fs::read(dirs[1].join(file)).unwrap());
+    }
+    let repository = Repository::open(dirs[0].to_str().unwrap()).unwrap();
+    let entries = repository.provisional_entries().unwrap();
+    assert_ne!(entries[0].id(), entries[1].id());
+    assert_eq!(repository.audit_log().unwrap().len(), 0);
+}